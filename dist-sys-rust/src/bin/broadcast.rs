@@ -1,45 +1,135 @@
-use anyhow::bail;
+use anyhow::{bail, Context};
+use maelstrom::{
+    bucket_values, main_loop, Action, Batch, Digest, Error, ErrorCode, Event, HyParView, InFlight, Init, Injector,
+    IntervalSet, Message, Node, Outbox, Output, Plumtree, Topology,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc;
-use std::collections::{HashMap, HashSet};
-use std::thread;
-use std::time::Duration;
-use std::io::{StdoutLock, Write, self, StdinLock, Lines};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message<T> {
-    src: String,
-    #[serde(rename = "dest")]
-    dst: String,
-    body: Body<T>,
+/// Overlay to compute at startup (see [`Topology`]); unset means Maelstrom's `topology` message is used.
+const TOPOLOGY_VAR: &str = "BROADCAST_TOPOLOGY";
+/// `gossip` (default) or `plumtree`.
+const MODE_VAR: &str = "BROADCAST_MODE";
+/// `static` (default) or `hyparview`.
+const MEMBERSHIP_VAR: &str = "BROADCAST_MEMBERSHIP";
+/// `push` (default) or `digest`.
+const RECONCILE_VAR: &str = "BROADCAST_RECONCILE";
+/// Bounds, in milliseconds, of the adaptive gossip interval used in gossip mode.
+const GOSSIP_MIN_VAR: &str = "BROADCAST_GOSSIP_MIN_MS";
+const GOSSIP_MAX_VAR: &str = "BROADCAST_GOSSIP_MAX_MS";
+/// Most values in one `batch` message of the per-peer outbox; `0` turns the outbox off, so values
+/// and acknowledgements go out in `gossip` and `gossip_ok` messages of their own.
+const BATCH_MAX_VAR: &str = "BROADCAST_BATCH_MAX";
+/// How often, in milliseconds, the outbox is flushed.
+const BATCH_INTERVAL_VAR: &str = "BROADCAST_BATCH_INTERVAL_MS";
+
+const DEFAULT_GOSSIP_MIN: Duration = Duration::from_millis(100);
+const DEFAULT_GOSSIP_MAX: Duration = Duration::from_millis(2000);
+const DEFAULT_BATCH_MAX: usize = 100;
+const DEFAULT_BATCH_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a gossiped value may go unacknowledged before the first round trip is measured.
+const INITIAL_RESEND_TIMEOUT: Duration = Duration::from_secs(1);
+/// In Plumtree mode gossip only repairs what eager pushes lost, so it can run less often.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often `IHave` announcements are flushed and missing messages checked.
+const LAZY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often HyParView pings its active view and tops it up from the passive one.
+const MEMBERSHIP_INTERVAL: Duration = Duration::from_secs(1);
+/// An active peer that does not answer a ping within this time is moved to the passive view.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Periodic push of what each neighbor has not acknowledged yet.
+    Gossip,
+    /// Epidemic broadcast trees: eager push along a spanning tree, lazy `IHave` to the other neighbors.
+    Plumtree,
 }
 
-impl<T> Message<T> where T: Serialize {
-    fn into_reply(self, id: Option<usize>) -> Self {
-        Message {
-            src: self.dst,
-            dst: self.src,
-            body: Body {
-                msg_id: id,
-                in_reply_to: self.body.msg_id,
-                payload: self.body.payload, 
-            }
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Membership {
+    /// Neighbors come from the overlay or Maelstrom's `topology` and never change.
+    Static,
+    /// Neighbors are the HyParView active view, which takes precedence over any overlay.
+    HyParView,
+}
 
-    fn send(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut *output, &self)?;
-        output.write_all(b"\n")?;
-        Ok(())
+/// How the periodic anti-entropy round reconciles a node with its neighbors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reconcile {
+    /// Send each neighbor what it has not acknowledged, as far as this node knows.
+    Push,
+    /// Exchange bucket hashes of `seen` and transfer only the buckets that differ, whatever
+    /// either side believed about the other.
+    Digest,
+}
+
+/// Startup configuration, read from the environment in `main`.
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    overlay: Option<Topology>,
+    mode: Mode,
+    membership: Membership,
+    reconcile: Reconcile,
+    gossip_min: Duration,
+    gossip_max: Duration,
+    /// `Some` when values and acknowledgements go through the outbox.
+    batch_max: Option<usize>,
+    batch_interval: Duration,
+}
+
+impl Config {
+    fn from_env() -> anyhow::Result<Self> {
+        let overlay = match std::env::var(TOPOLOGY_VAR) {
+            Ok(spec) => Some(spec.parse()?),
+            Err(_) => None,
+        };
+        let mode = match std::env::var(MODE_VAR).as_deref() {
+            Err(_) | Ok("gossip") => Mode::Gossip,
+            Ok("plumtree") => Mode::Plumtree,
+            Ok(other) => bail!("unknown {MODE_VAR} {other:?}, expected gossip or plumtree"),
+        };
+        let membership = match std::env::var(MEMBERSHIP_VAR).as_deref() {
+            Err(_) | Ok("static") => Membership::Static,
+            Ok("hyparview") => Membership::HyParView,
+            Ok(other) => bail!("unknown {MEMBERSHIP_VAR} {other:?}, expected static or hyparview"),
+        };
+        let reconcile = match std::env::var(RECONCILE_VAR).as_deref() {
+            Err(_) | Ok("push") => Reconcile::Push,
+            Ok("digest") => Reconcile::Digest,
+            Ok(other) => bail!("unknown {RECONCILE_VAR} {other:?}, expected push or digest"),
+        };
+        let gossip_min = millis_from_env(GOSSIP_MIN_VAR)?.unwrap_or(DEFAULT_GOSSIP_MIN);
+        let gossip_max = millis_from_env(GOSSIP_MAX_VAR)?.unwrap_or(DEFAULT_GOSSIP_MAX.max(gossip_min));
+        if gossip_min.is_zero() || gossip_min > gossip_max {
+            bail!("gossip interval bounds must satisfy 0 < {GOSSIP_MIN_VAR} <= {GOSSIP_MAX_VAR}");
+        }
+        let batch_max = match std::env::var(BATCH_MAX_VAR) {
+            Ok(max) => match max.parse() {
+                Ok(0) => None,
+                Ok(max) => Some(max),
+                Err(_) => bail!("{BATCH_MAX_VAR} must be a number of values"),
+            },
+            Err(_) => Some(DEFAULT_BATCH_MAX),
+        };
+        let batch_interval = millis_from_env(BATCH_INTERVAL_VAR)?.unwrap_or(DEFAULT_BATCH_INTERVAL);
+        if batch_interval.is_zero() {
+            bail!("{BATCH_INTERVAL_VAR} must be above zero");
+        }
+        Ok(Config { overlay, mode, membership, reconcile, gossip_min, gossip_max, batch_max, batch_interval })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Body<T> {
-    msg_id: Option<usize>,
-    in_reply_to: Option<usize>,
-    #[serde(flatten)]
-    payload: T,
+fn millis_from_env(var: &str) -> anyhow::Result<Option<Duration>> {
+    match std::env::var(var) {
+        Ok(millis) => {
+            let millis = millis.parse().with_context(|| format!("{var} must be a number of milliseconds"))?;
+            Ok(Some(Duration::from_millis(millis)))
+        }
+        Err(_) => Ok(None),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,179 +142,515 @@ enum Payload  {
     BroadcastOk,
     Topology { topology: HashMap<String, Vec<String>> },
     TopologyOk,
-    Gossip { seen: Vec<usize> }
+    Gossip { seen: IntervalSet },
+    GossipOk { seen: IntervalSet },
+    Digest { buckets: Digest },
+    /// Buckets whose hashes differ from the digest's, and the replier's values in them.
+    DigestOk { differing: IntervalSet, seen: IntervalSet },
+    /// Outbox flush: values for the receiver and acknowledgements of the values it sent.
+    Batch { seen: IntervalSet, acked: IntervalSet },
+    Push { message: usize },
+    IHave { messages: Vec<usize> },
+    Graft { messages: Vec<usize> },
+    Prune,
+    Join,
+    ForwardJoin { node: String, ttl: usize },
+    Neighbor { high_priority: bool },
+    NeighborOk { accepted: bool },
+    Disconnect,
+    Shuffle { origin: String, nodes: Vec<String>, ttl: usize },
+    ShuffleOk { nodes: Vec<String> },
+    Ping,
+    Pong,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag="type")]
-#[serde(rename_all = "snake_case")]
-enum InitPayload {
-    Init(Init),
-    InitOk,
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Gossip,
+    /// Adaptive gossip round in gossip mode; only the latest one scheduled runs.
+    Round { generation: u64 },
+    Flush,
+    Lazy,
+    Membership,
+    Ping { peer: String, alive: bool },
+    NeighborReply { peer: String, accepted: bool },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Init {
-    node_id: String,
-    node_ids: Vec<String>,
+/// Gossips `seen` to `peer` at once, or queues it when there is an outbox.
+fn send_values(
+    outbox: &mut Option<Outbox>,
+    peer: &str,
+    seen: IntervalSet,
+    output: &mut Output<InjectedPayload>,
+) -> anyhow::Result<()> {
+    match outbox {
+        Some(outbox) => {
+            for batch in outbox.push(peer, &seen) {
+                send_batch(peer, batch, output)?;
+            }
+            Ok(())
+        }
+        None => {
+            output.send(peer, Payload::Gossip { seen })?;
+            Ok(())
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-enum Event {
-    Message(Message<Payload>),
-    Gossip,
-    Eof,
+fn send_batch(peer: &str, batch: Batch, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+    output.send(peer, Payload::Batch { seen: batch.seen, acked: batch.acked })
 }
 
-#[derive(Default)]
-struct Node {
-    id: usize, 
+struct BroadcastNode {
     node_id: String,
-    seen: HashSet<usize>,
-    known: HashMap<String, HashSet<usize>>,
-    neighborhood: Vec<String>, 
+    seen: IntervalSet,
+    known: HashMap<String, IntervalSet>,
+    neighborhood: Vec<String>,
+    /// Set when the overlay was computed at startup, in which case `topology` messages are ignored.
+    overlay: Option<Topology>,
+    /// `Some` in Plumtree mode.
+    plumtree: Option<Plumtree>,
+    /// `Some` with HyParView membership, whose active view is then the `neighborhood`.
+    hyparview: Option<HyParView>,
+    reconcile: Reconcile,
+    /// `Some` when batching values and acknowledgements per peer.
+    outbox: Option<Outbox>,
+    inject: Injector<InjectedPayload>,
+    /// Values gossiped to each neighbor that it has not acknowledged yet.
+    in_flight: InFlight,
+    /// Delay until the next gossip round in gossip mode, kept within `gossip_min..=gossip_max`.
+    gossip_interval: Duration,
+    /// Generation and due time of the latest round scheduled in gossip mode.
+    round: (u64, Instant),
+    gossip_min: Duration,
+    gossip_max: Duration,
 }
 
-impl Node {
-    fn from_init(message: Init, tx: mpsc::Sender<Event>) -> Self {
-        thread::spawn(move || {
-            loop { 
-                thread::sleep(Duration::from_millis(300));
-                if tx.send(Event::Gossip).is_err() {
-                    break;
-                }
+impl Node<Config, Payload, InjectedPayload> for BroadcastNode {
+    fn from_init(config: Config, message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        let overlay = config.overlay;
+        let hyparview = match config.membership {
+            Membership::Static => None,
+            Membership::HyParView => {
+                inject.every(MEMBERSHIP_INTERVAL, InjectedPayload::Membership);
+                // Everybody joins through the first node.
+                let contact = message.node_ids.first().cloned().unwrap_or_default();
+                Some(HyParView::new(&message.node_id, &contact))
+            }
+        };
+        let neighborhood = overlay
+            .filter(|_| hyparview.is_none())
+            .map(|topology| topology.neighbors(&message.node_id, &message.node_ids))
+            .unwrap_or_default();
+        let plumtree = match config.mode {
+            Mode::Gossip => None,
+            Mode::Plumtree => {
+                inject.every(LAZY_INTERVAL, InjectedPayload::Lazy);
+                Some(Plumtree::new(&neighborhood))
             }
+        };
+        let outbox = config.batch_max.map(|max_batch| {
+            inject.every(config.batch_interval, InjectedPayload::Flush);
+            Outbox::new(max_batch)
         });
-        Node {
-            id: 1,
+        match (config.mode, config.reconcile) {
+            (Mode::Gossip, Reconcile::Push) => inject.after(config.gossip_min, InjectedPayload::Round { generation: 0 }),
+            _ => inject.every(ANTI_ENTROPY_INTERVAL, InjectedPayload::Gossip),
+        }
+        Ok(BroadcastNode {
             node_id: message.node_id,
-            seen:HashSet::new(),
+            seen: IntervalSet::new(),
             known: message
                 .node_ids
                 .into_iter()
-                .map(|node_id| (node_id, HashSet::new()))
+                .map(|node_id| (node_id, IntervalSet::new()))
                 .collect(),
-            neighborhood: Vec::new(),
+            neighborhood,
+            overlay,
+            plumtree,
+            hyparview,
+            reconcile: config.reconcile,
+            outbox,
+            inject,
+            in_flight: InFlight::new(INITIAL_RESEND_TIMEOUT),
+            gossip_interval: config.gossip_min,
+            round: (0, Instant::now() + config.gossip_min),
+            gossip_min: config.gossip_min,
+            gossip_max: config.gossip_max,
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event { 
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Gossip) => match self.reconcile {
+                Reconcile::Push => self.gossip_tick(output)?,
+                Reconcile::Digest if !self.seen.is_empty() => {
+                    let buckets = Digest::of(&self.seen);
+                    for node in &self.neighborhood {
+                        output.send(node, Payload::Digest { buckets: buckets.clone() })?;
+                    }
+                }
+                Reconcile::Digest => {}
+            },
+            Event::Injected(InjectedPayload::Round { generation }) => {
+                if generation == self.round.0 {
+                    self.gossip_tick(output)?;
+                }
+            }
+            Event::Injected(InjectedPayload::Flush) => {
+                if let Some(outbox) = &mut self.outbox {
+                    for (peer, batch) in outbox.flush() {
+                        send_batch(&peer, batch, output)?;
+                    }
+                }
+            }
+            Event::Injected(InjectedPayload::Lazy) => {
+                self.lazy_tick(output)?;
+            }
+            Event::Injected(InjectedPayload::Membership) => {
+                self.membership(output, |view| view.tick())?;
+            }
+            Event::Injected(InjectedPayload::Ping { peer, alive }) => {
+                if !alive {
+                    self.membership(output, |view| {
+                        view.demote(&peer);
+                        Vec::new()
+                    })?;
+                }
+            }
+            Event::Injected(InjectedPayload::NeighborReply { peer, accepted }) => {
+                self.membership(output, |view| view.neighbor_reply(&peer, accepted))?;
+            }
         }
+        Ok(())
     }
+}
 
-    fn process(&mut self, msg: Message<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let mut reply = msg.into_reply(Some(self.id));
+impl BroadcastNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let src = msg.src.clone();
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
         match reply.body.payload {
             Payload::Read => {
                 reply.body.payload = Payload::ReadOk { 
                     messages: self.seen
                         .iter()
-                        .copied()
                         .collect(), 
                 };
                 reply.send(output)?;
             }
             Payload::Broadcast { message } => {
-                self.seen.insert(message);
+                if self.seen.insert(message) {
+                    self.spread(message, None, output)?;
+                }
                 reply.body.payload = Payload::BroadcastOk;
                 reply.send(output)?;
             }
+            Payload::Topology { .. } if self.overlay.is_some() || self.hyparview.is_some() => {
+                reply.body.payload = Payload::TopologyOk;
+                reply.send(output)?;
+            }
             Payload::Topology { mut topology } => {
                 self.neighborhood = topology
                     .remove(&self.node_id)
-                    .unwrap_or_else(|| panic!("No topology sent to Node {}", self.node_id));
+                    .ok_or_else(|| Error::new(
+                        ErrorCode::MalformedRequest,
+                        format!("No topology sent to Node {}", self.node_id),
+                    ))?;
+                if let Some(plumtree) = &mut self.plumtree {
+                    *plumtree = Plumtree::new(&self.neighborhood);
+                }
                 reply.body.payload = Payload::TopologyOk; 
                 reply.send(output)?;
             }
             Payload::Gossip { seen } => {
-                self.seen.extend(seen);
+                self.acknowledge(&src, &seen);
+                self.merge(&seen, output)?;
+                reply.body.payload = Payload::GossipOk { seen };
+                reply.send(output)?;
+            }
+            Payload::GossipOk { seen } => {
+                self.acknowledge(&src, &seen);
+                self.in_flight.acked(&src, &seen, Instant::now());
+            }
+            Payload::Digest { buckets } => {
+                let differing = buckets.differing(&Digest::of(&self.seen));
+                let covered = bucket_values(&differing);
+                // Matching buckets hold the same values on both sides.
+                self.acknowledge(&src, &self.seen.difference(&covered));
+                if !differing.is_empty() {
+                    reply.body.payload = Payload::DigestOk { differing, seen: self.seen.intersection(&covered) };
+                    reply.send(output)?;
+                }
+            }
+            Payload::DigestOk { differing, seen } => {
+                let covered = bucket_values(&differing);
+                let missing = self.seen.intersection(&covered).difference(&seen);
+                self.acknowledge(&src, &self.seen.difference(&covered));
+                self.acknowledge(&src, &seen);
+                self.merge(&seen, output)?;
+                if !missing.is_empty() {
+                    send_values(&mut self.outbox, &src, missing, output)?;
+                }
             }
-            Payload::ReadOk { .. } | Payload::BroadcastOk | Payload::TopologyOk => {}
+            Payload::Batch { seen, acked } => {
+                self.acknowledge(&src, &seen);
+                self.acknowledge(&src, &acked);
+                self.in_flight.acked(&src, &acked, Instant::now());
+                self.merge(&seen, output)?;
+                if let Some(outbox) = self.outbox.as_mut().filter(|_| !seen.is_empty()) {
+                    outbox.ack(&src, &seen);
+                }
+            }
+            Payload::Push { message } => {
+                self.acknowledge(&src, &IntervalSet::from_iter([message]));
+                let new = self.seen.insert(message);
+                if let Some(plumtree) = &mut self.plumtree {
+                    if new {
+                        plumtree.graft(&src);
+                    } else {
+                        plumtree.prune(&src);
+                        output.send(&src, Payload::Prune)?;
+                    }
+                }
+                if new {
+                    self.spread(message, Some(&src), output)?;
+                }
+            }
+            Payload::IHave { messages } => {
+                self.acknowledge(&src, &messages.iter().copied().collect());
+                if let Some(plumtree) = &mut self.plumtree {
+                    plumtree.announced(&src, &messages, &self.seen, Instant::now());
+                }
+            }
+            Payload::Graft { messages } => {
+                if let Some(plumtree) = &mut self.plumtree {
+                    plumtree.graft(&src);
+                }
+                for message in messages.into_iter().filter(|m| self.seen.contains(*m)) {
+                    output.send(&src, Payload::Push { message })?;
+                }
+            }
+            Payload::Prune => {
+                if let Some(plumtree) = &mut self.plumtree {
+                    plumtree.prune(&src);
+                }
+            }
+            Payload::Join => {
+                self.membership(output, |view| view.join(&src))?;
+            }
+            Payload::ForwardJoin { node, ttl } => {
+                self.membership(output, |view| view.forward_join(&src, &node, ttl))?;
+            }
+            Payload::Neighbor { high_priority } => {
+                let mut accepted = false;
+                self.membership(output, |view| {
+                    let (taken, actions) = view.neighbor_request(&src, high_priority);
+                    accepted = taken;
+                    actions
+                })?;
+                reply.body.payload = Payload::NeighborOk { accepted };
+                reply.send(output)?;
+            }
+            Payload::Disconnect => {
+                self.membership(output, |view| {
+                    view.demote(&src);
+                    Vec::new()
+                })?;
+            }
+            Payload::Shuffle { origin, nodes, ttl } => {
+                self.membership(output, |view| view.shuffle(&src, &origin, &nodes, ttl))?;
+            }
+            Payload::ShuffleOk { nodes } => {
+                self.membership(output, |view| {
+                    view.shuffle_reply(&nodes);
+                    Vec::new()
+                })?;
+            }
+            Payload::Ping => {
+                if self.hyparview.is_some() && !self.neighborhood.contains(&src) {
+                    output.send(&src, Payload::Disconnect)?;
+                }
+                reply.body.payload = Payload::Pong;
+                reply.send(output)?;
+            }
+            Payload::ReadOk { .. }
+            | Payload::BroadcastOk
+            | Payload::TopologyOk
+            | Payload::NeighborOk { .. }
+            | Payload::Pong => {}
         }
-        self.id += 1;
         Ok(())
     }
 
-    fn handle_event(&mut self, event: Event, output: &mut StdoutLock) -> anyhow::Result<()> {
-        match event { 
-            Event::Eof => {}
-            Event::Message(msg) => {
-                self.process(msg, output)?;
+    /// Sends each neighbor what it is not known to hold, except values sent to it less than a
+    /// round trip ago. In gossip mode the next round is scheduled here: sooner while some values
+    /// are overdue, later while every neighbor is up to date, and no sooner or later while
+    /// acknowledgements are still on the way.
+    fn gossip_tick(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let (mut overdue, mut unacknowledged) = (false, false);
+        for node in &self.neighborhood {
+            let missing = self.unacknowledged(node);
+            unacknowledged |= !missing.is_empty();
+            let resend = self.in_flight.overdue(node, &missing, now);
+            if !resend.is_empty() {
+                overdue = true;
+                self.in_flight.sent(node, &resend, now);
+                send_values(&mut self.outbox, node, resend, output)?;
             }
-            Event::Gossip => {
-                for node in &self.neighborhood {
-                    if let Some(gossip) = self.create_gossip(node) {
-                        gossip.send(output)?;
-                    }
-                }
+        }
+        if self.plumtree.is_none() {
+            if overdue {
+                self.gossip_interval = (self.gossip_interval / 2).max(self.gossip_min);
+            } else if !unacknowledged {
+                self.gossip_interval = (self.gossip_interval * 2).min(self.gossip_max);
             }
+            self.schedule_round(self.gossip_interval);
         }
         Ok(())
     }
 
-    fn create_gossip(&self, node: &String) -> Option<Message<Payload>> {
-        let known_by_n = &self.known[node];
-        let not_known_by_n: Vec<usize> = self.seen
-            .iter()
-            .copied()
-            .filter(|x| !known_by_n.contains(x))
-            .collect();
-        if !not_known_by_n.is_empty() {
-            Some(Message {
-                src: self.node_id.clone(),
-                dst: node.clone(),
-                body: Body {
-                    msg_id: None,
-                    in_reply_to: None,
-                    payload: Payload::Gossip { 
-                        seen: not_known_by_n,                
-                    }
+    /// Schedules the next gossip-mode round in `delay`, superseding the one scheduled before.
+    fn schedule_round(&mut self, delay: Duration) {
+        let generation = self.round.0 + 1;
+        self.round = (generation, Instant::now() + delay);
+        self.inject.after(delay, InjectedPayload::Round { generation });
+    }
+
+    /// Adds `seen` to the values held, forwarding the new ones at once in gossip mode.
+    fn merge(&mut self, seen: &IntervalSet, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let fresh = seen.difference(&self.seen);
+        self.seen.union(&fresh);
+        if self.plumtree.is_none() && !fresh.is_empty() {
+            self.forward(&fresh, output)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `update` on the HyParView membership, if any, takes its active view as the
+    /// neighborhood and sends the messages it asks for.
+    fn membership(
+        &mut self,
+        output: &mut Output<InjectedPayload>,
+        update: impl FnOnce(&mut HyParView) -> Vec<Action>,
+    ) -> anyhow::Result<()> {
+        let Some(view) = &mut self.hyparview else {
+            return Ok(());
+        };
+        let actions = update(view);
+        if let Some(plumtree) = &mut self.plumtree {
+            for peer in self.neighborhood.iter().filter(|peer| !view.is_active(peer)) {
+                plumtree.forget(peer);
+            }
+            for peer in view.active().iter().filter(|peer| !self.neighborhood.contains(peer)) {
+                plumtree.graft(peer);
+            }
+        }
+        self.neighborhood = view.active().to_vec();
+
+        for action in actions {
+            match action {
+                Action::Join { to } => output.send(&to, Payload::Join)?,
+                Action::ForwardJoin { to, node, ttl } => output.send(&to, Payload::ForwardJoin { node, ttl })?,
+                Action::Connect { to, high_priority } => {
+                    let peer = to.clone();
+                    output.call(&to, Payload::Neighbor { high_priority }, PING_TIMEOUT, move |result| {
+                        InjectedPayload::NeighborReply {
+                            peer,
+                            accepted: matches!(result, Ok(Payload::NeighborOk { accepted: true })),
+                        }
+                    })?;
+                }
+                Action::Disconnect { to } => output.send(&to, Payload::Disconnect)?,
+                Action::Shuffle { to, origin, nodes, ttl } => output.send(&to, Payload::Shuffle { origin, nodes, ttl })?,
+                Action::ShuffleReply { to, nodes } => output.send(&to, Payload::ShuffleOk { nodes })?,
+                Action::Ping { to } => {
+                    let peer = to.clone();
+                    output.call(&to, Payload::Ping, PING_TIMEOUT, move |result: Result<Payload, Error>| {
+                        InjectedPayload::Ping { peer, alive: result.is_ok() }
+                    })?;
                 }
-            })
-        } else { None }
+            }
+        }
+        Ok(())
     }
-}
 
-fn main() -> anyhow::Result<()> {
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let (tx, rx) = mpsc::channel();
-
-    let init_msg = wait_for_initialization(&mut stdin.lines(), &mut stdout.lock())
-        .expect("Expected init message");
-    let mut node = Node::from_init(init_msg, tx.clone());
-
-    let main_thread = thread::spawn(move || {
-        let stdin = io::stdin();
-        for line in stdin.lines() {
-            let input = line.expect("no message received");
-            let msg: Message<Payload> = serde_json::from_str(&input)
-                .expect("Error deserializing message");
-            if tx.send(Event::Message(msg)).is_err() {
-                bail!("Error tx send");
+    /// Sends a newly seen message on right away: pushed to the eager peers and announced to the
+    /// lazy ones in Plumtree mode, gossiped to every neighbor otherwise.
+    fn spread(&mut self, message: usize, from: Option<&str>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(plumtree) = &mut self.plumtree else {
+            return self.forward(&IntervalSet::from_iter([message]), output);
+        };
+        for peer in plumtree.spread(message, from) {
+            output.send(&peer, Payload::Push { message })?;
+        }
+        Ok(())
+    }
+
+    /// Gossips `fresh` values at once to each neighbor not known to hold them, without waiting
+    /// for the outbox to be flushed; whatever else is queued for that neighbor goes along. With
+    /// push reconciliation the gossip interval drops back to `gossip_min`, and the next round is
+    /// brought forward to it unless it is due sooner.
+    fn forward(&mut self, fresh: &IntervalSet, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let now = Instant::now();
+        for node in &self.neighborhood {
+            let seen = match self.known.get(node) {
+                Some(known) => fresh.difference(known),
+                None => fresh.clone(),
             };
+            if seen.is_empty() {
+                continue;
+            }
+            self.in_flight.sent(node, &seen, now);
+            send_values(&mut self.outbox, node, seen, output)?;
+            if let Some(batch) = self.outbox.as_mut().and_then(|outbox| outbox.take(node)) {
+                send_batch(node, batch, output)?;
+            }
+        }
+        if self.reconcile == Reconcile::Push {
+            self.gossip_interval = self.gossip_min;
+            if self.round.1 > now + self.gossip_min {
+                self.schedule_round(self.gossip_min);
+            }
         }
-        let _ = tx.send(Event::Eof);
         Ok(())
-    });
+    }
 
-    for event in rx {
-        node
-            .handle_event(event, &mut stdout.lock())
-            .expect("node failed processing message");
+    /// Flushes pending `IHave`s and grafts every announced message that is overdue.
+    fn lazy_tick(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(plumtree) = &mut self.plumtree else {
+            return Ok(());
+        };
+        for (peer, messages) in plumtree.take_announcements() {
+            output.send(&peer, Payload::IHave { messages })?;
+        }
+        for (peer, messages) in plumtree.due_grafts(&self.seen, Instant::now()) {
+            output.send(&peer, Payload::Graft { messages })?;
+        }
+        Ok(())
     }
 
-    main_thread.join().expect("Error running main thread")?;
+    /// Records that `node` holds `seen`, so they are never gossiped to it again.
+    fn acknowledge(&mut self, node: &str, seen: &IntervalSet) {
+        if let Some(known) = self.known.get_mut(node) {
+            known.union(seen);
+        }
+    }
 
-    Ok(())
+    /// Values `node` is not known to hold.
+    fn unacknowledged(&self, node: &str) -> IntervalSet {
+        match self.known.get(node) {
+            Some(known_by_n) => self.seen.difference(known_by_n),
+            None => self.seen.clone(),
+        }
+    }
 }
 
-fn wait_for_initialization(input: &mut Lines<StdinLock>, output: &mut StdoutLock) -> anyhow::Result<Init> {
-    let msg: Message<InitPayload> = serde_json::from_str(
-        &input
-        .next()
-        .expect("no message received")?)?;
-    let InitPayload::Init(init_msg) = msg.body.payload.clone() else {
-        bail!("Expected init message")
-    };
-    let mut reply = msg.into_reply(Some(0));
-    reply.body.payload = InitPayload::InitOk;
-    reply.send(output)?;
-    Ok(init_msg)
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, BroadcastNode, _, _>(Config::from_env()?)
 }
@@ -0,0 +1,197 @@
+use maelstrom::{main_loop, Event, Init, Injector, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Add { element: Value },
+    AddOk,
+    Read,
+    ReadOk { value: Vec<Value> },
+    Delta { seq: u64, elements: Vec<Value> },
+    DeltaOk { seq: u64 },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Gossip,
+}
+
+struct GSetNode {
+    /// Every element, keyed by its JSON encoding since `Value` cannot be hashed.
+    elements: HashMap<String, Value>,
+    /// Elements added on this node, by the sequence number of the delta that introduced them.
+    deltas: BTreeMap<u64, Value>,
+    next_seq: u64,
+    /// Highest delta sequence number each peer has acknowledged.
+    acked: HashMap<String, u64>,
+}
+
+impl Node<(), Payload, InjectedPayload> for GSetNode {
+    fn from_init(_state: (), message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        inject.every(Duration::from_millis(300), InjectedPayload::Gossip);
+        Ok(GSetNode {
+            elements: HashMap::new(),
+            deltas: BTreeMap::new(),
+            next_seq: 1,
+            acked: message.node_ids
+                .into_iter()
+                .filter(|n| *n != message.node_id)
+                .map(|n| (n, 0))
+                .collect(),
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Gossip) => {
+                for (peer, acked) in &self.acked {
+                    if let Some(delta) = self.create_delta(*acked) {
+                        output.send(peer, delta)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GSetNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let src = msg.src.clone();
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::Add { element } => {
+                if self.insert(element.clone()) {
+                    self.deltas.insert(self.next_seq, element);
+                    self.next_seq += 1;
+                }
+                reply.body.payload = Payload::AddOk;
+                reply.send(output)?;
+            }
+            Payload::Read => {
+                reply.body.payload = Payload::ReadOk {
+                    value: self.elements
+                        .values()
+                        .cloned()
+                        .collect(),
+                };
+                reply.send(output)?;
+            }
+            Payload::Delta { seq, elements } => {
+                for element in elements {
+                    self.insert(element);
+                }
+                reply.body.payload = Payload::DeltaOk { seq };
+                reply.send(output)?;
+            }
+            Payload::DeltaOk { seq } => {
+                if let Some(acked) = self.acked.get_mut(&src) {
+                    *acked = (*acked).max(seq);
+                }
+                self.compact();
+            }
+            Payload::AddOk | Payload::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Returns whether `element` was new.
+    fn insert(&mut self, element: Value) -> bool {
+        let key = element.to_string();
+        if self.elements.contains_key(&key) {
+            return false;
+        }
+        self.elements.insert(key, element);
+        true
+    }
+
+    /// Joins every delta after `acked` into one message, tagged with the last sequence number it covers.
+    fn create_delta(&self, acked: u64) -> Option<Payload> {
+        let (seq, _) = self.deltas.last_key_value()?;
+        if *seq <= acked {
+            return None;
+        }
+        Some(Payload::Delta {
+            seq: *seq,
+            elements: self.deltas
+                .range(acked + 1..)
+                .map(|(_, element)| element.clone())
+                .collect(),
+        })
+    }
+
+    /// Forgets the deltas every peer has acknowledged.
+    fn compact(&mut self) {
+        let acked = self.acked.values().copied().min().unwrap_or(u64::MAX);
+        self.deltas = self.deltas.split_off(&(acked.saturating_add(1)));
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, GSetNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+    use serde_json::json;
+
+    type GSet = TestNode<GSetNode, (), Payload, InjectedPayload>;
+
+    fn deltas_to(set: &mut GSet, peer: &str) -> Vec<Value> {
+        set.inject(InjectedPayload::Gossip);
+        set.sent()
+            .into_iter()
+            .filter(|(dest, _)| dest == peer)
+            .map(|(_, body)| body)
+            .collect()
+    }
+
+    #[test]
+    fn a_delta_is_resent_until_acknowledged() {
+        let mut n1 = GSet::start((), "n1", &["n1", "n2", "n3"]);
+        n1.deliver("c1", 1, Payload::Add { element: json!(1) });
+        n1.deliver("c1", 2, Payload::Add { element: json!(2) });
+        n1.sent();
+
+        for _ in 0..2 {
+            let deltas = deltas_to(&mut n1, "n2");
+            assert_eq!(deltas.len(), 1);
+            assert_eq!(deltas[0]["seq"], 2);
+            assert_eq!(deltas[0]["elements"], json!([1, 2]));
+        }
+
+        n1.deliver("n2", 1, Payload::DeltaOk { seq: 1 });
+        let deltas = deltas_to(&mut n1, "n2");
+        assert_eq!(deltas[0]["elements"], json!([2]));
+
+        n1.deliver("n2", 2, Payload::DeltaOk { seq: 2 });
+        assert!(deltas_to(&mut n1, "n2").is_empty());
+        assert_eq!(deltas_to(&mut n1, "n3")[0]["elements"], json!([1, 2]));
+    }
+
+    #[test]
+    fn compact_keeps_deltas_until_every_peer_acknowledges_them() {
+        let mut n1 = GSet::start((), "n1", &["n1", "n2", "n3"]);
+        for (msg_id, element) in [1, 2, 3].into_iter().enumerate() {
+            n1.deliver("c1", msg_id, Payload::Add { element: json!(element) });
+        }
+
+        n1.deliver("n2", 1, Payload::DeltaOk { seq: 3 });
+        assert_eq!(n1.node.deltas.keys().copied().collect::<Vec<_>>(), [1, 2, 3]);
+
+        n1.deliver("n3", 1, Payload::DeltaOk { seq: 2 });
+        assert_eq!(n1.node.deltas.keys().copied().collect::<Vec<_>>(), [3]);
+        assert_eq!(n1.node.elements.len(), 3);
+    }
+}
@@ -1,281 +1,410 @@
 use anyhow::bail;
-use regex::Regex;
+use maelstrom::{main_loop, read_number, replace_file, Event, Init, Injector, KvClient, KvError, KvService, Message, Node, Output};
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::time::Duration;
-use std::thread;
-use std::io::{StdoutLock, Write, self, StdinLock, Lines};
 
-const LIN_KV: &str = "lin-kv";
-const KEY: &str = "counter";
-const ERROR_MSG: &str = r"current value (?P<current>\d+) is not (?P<expected>\d+)";
+/// `commit` (default) or `immediate`.
+const ACK_VAR: &str = "COUNTER_ACK";
+/// Where `immediate` mode journals the accepted total, for a restarted node to flush again.
+/// Unset means the system temporary directory.
+const STATE_DIR_VAR: &str = "COUNTER_STATE_DIR";
+/// Delay before the first retry of a failed `cas`, doubled on every further failure.
+const RETRY_BASE: Duration = Duration::from_millis(50);
+const RETRY_MAX: Duration = Duration::from_secs(2);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message<T> {
-    src: String,
-    #[serde(rename = "dest")]
-    dst: String,
-    body: Body<T>,
+/// When an `add` is answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AckMode {
+    /// Once its delta is in seq-kv.
+    Commit,
+    /// Once the new total of accepted adds is in a journal under `dir`; the delta stays queued
+    /// until it is flushed, and a restarted node flushes the journaled total again.
+    Immediate { dir: PathBuf },
 }
 
-impl<T> Message<T> where T: Serialize {
-    fn into_reply(self, id: Option<usize>) -> Self {
-        Message {
-            src: self.dst,
-            dst: self.src,
-            body: Body {
-                msg_id: id,
-                in_reply_to: self.body.msg_id,
-                payload: self.body.payload, 
-            }
-        }
-    }
-
-    fn send(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut *output, &self)?;
-        output.write_all(b"\n")?;
-        Ok(())
+impl AckMode {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(match std::env::var(ACK_VAR).as_deref() {
+            Err(_) | Ok("commit") => AckMode::Commit,
+            Ok("immediate") => AckMode::Immediate {
+                dir: std::env::var_os(STATE_DIR_VAR).map_or_else(std::env::temp_dir, PathBuf::from),
+            },
+            Ok(other) => bail!("unknown {ACK_VAR} {other:?}, expected commit or immediate"),
+        })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Body<T> {
-    msg_id: Option<usize>,
-    in_reply_to: Option<usize>,
-    #[serde(flatten)]
-    payload: T,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Read { key: Option<String> },
+    Read,
     ReadOk { value: usize },
     Add { delta: usize },
     AddOk,
-    Cas { 
-        key: String,
-        from: usize, 
-        to:usize,
-        create_if_not_exists: bool,
-    },
-    CasOk,
-    Error {
-        code: usize,
-        text: String,
-    },
-    Gossip { 
-        value: usize 
-    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag="type")]
-#[serde(rename_all = "snake_case")]
-enum InitPayload {
-    Init(Init),
-    InitOk,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Init {
-    node_id: String,
-    node_ids: Vec<String>,
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Tick,
+    PeerRead {
+        node: String,
+        result: Result<usize, KvError<usize>>,
+    },
+    CasResult {
+        to: usize,
+        result: Result<(), KvError<usize>>,
+    },
+    Retry,
 }
 
-#[derive(Debug, Clone)]
-enum Event {
-    Message(Message<Payload>),
-    Read,
-    Eof,
+/// An accepted `add` not yet in seq-kv.
+struct Delta {
+    amount: usize,
+    /// Request to answer once committed; `None` when it was answered on arrival.
+    request: Option<Message<Payload>>,
+    /// Failed `cas` attempts that carried this delta.
+    attempts: u32,
 }
 
-struct Node {
-    id: usize, 
+/// Each node owns the seq-kv key holding the sum of the adds it accepted, and is the only one
+/// writing it. Writes carry the whole sum, so retrying one can never count an add twice.
+struct CounterNode {
     node_id: String,
-    neighbors: Vec<String>,
-    counter: usize,
-    last_value: usize,
+    kv: KvClient,
+    /// File holding the sum of every `add` this node answered; only kept in immediate mode,
+    /// where an `add` is answered once it is counted there.
+    journal: Option<PathBuf>,
+    /// Sum in the journal.
+    accepted: usize,
+    /// Set after a restart until seq-kv confirms how much of the journaled total it already holds.
+    recovering: bool,
+    inject: Injector<InjectedPayload>,
+    peers: Vec<String>,
+    /// Value of this node's key as last confirmed by seq-kv.
+    committed: usize,
+    /// Deltas not committed yet, oldest first. Whatever is queued when a `cas` goes out is
+    /// coalesced into it.
+    queue: VecDeque<Delta>,
+    /// Target of the `cas` in flight; only one at a time, so writes land in order.
+    flushing: Option<usize>,
+    /// Set while waiting out the backoff after a failed `cas`.
+    backing_off: bool,
+    /// Highest value read from each peer's key.
+    totals: HashMap<String, usize>,
 }
 
-impl Node {
-    fn from_init(message: Init, tx: mpsc::Sender<Event>) -> Self {
-        thread::spawn(move || {
-            loop { 
-                thread::sleep(Duration::from_millis(300));
-                if tx.send(Event::Read).is_err() {
-                    break;
-                }
-            }
-        });
-        Node {
-            id: 1,
-            neighbors: message.node_ids
+impl Node<AckMode, Payload, InjectedPayload> for CounterNode {
+    fn from_init(ack: AckMode, message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        inject.every(Duration::from_millis(300), InjectedPayload::Tick);
+        let journal = match ack {
+            AckMode::Commit => None,
+            AckMode::Immediate { dir } => Some(dir.join(format!("grow-counter-{}.total", message.node_id))),
+        };
+        let accepted = match &journal {
+            Some(path) => read_number(path, "total")?.unwrap_or(0),
+            None => 0,
+        };
+        // What seq-kv already holds of the journaled total is unknown until the first `cas`
+        // fails, so all of it is queued as one delta.
+        let queue = match accepted {
+            0 => VecDeque::new(),
+            amount => VecDeque::from([Delta { amount, request: None, attempts: 0 }]),
+        };
+        Ok(CounterNode {
+            peers: message.node_ids
                 .into_iter()
                 .filter(|n| n != &message.node_id)
                 .collect(),
             node_id: message.node_id,
-            counter: 0,
-            last_value: 0,
-        }
+            kv: KvClient::new(KvService::Seq),
+            journal,
+            accepted,
+            recovering: accepted > 0,
+            inject,
+            committed: 0,
+            queue,
+            flushing: None,
+            backing_off: false,
+            totals: HashMap::new(),
+        })
     }
 
-    fn process(&mut self, msg: Message<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let mut reply = msg.into_reply(Some(self.id));
-        match reply.body.payload {
-            Payload::Read { .. } => {
-                reply.body.payload = Payload::ReadOk { 
-                    value: self.counter
-                };
-                reply.send(output)?;
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Injected(InjectedPayload::Tick) => {
+                self.flush(output)?;
+                for peer in &self.peers {
+                    let node = peer.clone();
+                    self.kv.read(output, key(peer), move |result| InjectedPayload::PeerRead { node, result })?;
+                }
             }
-            Payload::Add { delta } => {
-                if delta > 0 {
-                    self.last_value = delta;
-                    self.add_to_store(delta, output)?;
-                    self.counter += delta;
-                    self.send_gossip(self.last_value, output)?;
+            Event::Injected(InjectedPayload::PeerRead { node, result }) => {
+                // seq-kv may serve stale values; a key only grows, so keep the highest seen.
+                if let Ok(value) = result {
+                    let total = self.totals.entry(node).or_default();
+                    *total = (*total).max(value);
                 }
-                reply.body.payload = Payload::AddOk;
-                reply.send(output)?;
             }
-            Payload::Gossip { value } => {
-                self.counter += value;
+            Event::Injected(InjectedPayload::CasResult { to, result }) => {
+                self.flushing = None;
+                match result {
+                    Ok(()) => {
+                        self.recovering = false;
+                        self.commit(to, output)?;
+                    }
+                    // An earlier attempt whose reply was lost did land: pick up from there.
+                    Err(KvError::PreconditionFailed { current: Some(current) }) if self.is_prefix_sum(current) => {
+                        self.recovering = false;
+                        self.commit(current, output)?;
+                    }
+                    Err(KvError::PreconditionFailed { current: Some(current) }) if self.is_recovered_part(current) => {
+                        self.adopt(current);
+                    }
+                    Err(error) => {
+                        eprintln!("{} failed to commit {to}: {error:?}", self.node_id);
+                        self.back_off();
+                    }
+                }
+                self.flush(output)?;
             }
-            Payload::ReadOk { value } => {
-                self.counter = value;
+            Event::Injected(InjectedPayload::Retry) => {
+                self.backing_off = false;
+                self.flush(output)?;
             }
-            Payload::Error { code, text } => {
-                if code == 20 {
-                    self.add_to_store(0, output)?;
-                }
-                else if code == 22 {
-                    let re = Regex::new(ERROR_MSG).unwrap();
-                    if let Some(matches) = re.captures(&text) {
-                        if let Ok(current_value) = matches["current"].parse::<usize>() {
-                            self.counter = current_value;
-                        }
-                        if self.last_value > 0 {
-                            self.add_to_store(self.last_value, output)?;
-                            self.send_gossip(self.last_value, output)?;
-                        }
-                    } 
-                }
+            Event::Message(msg) => {
+                self.process(msg, output)?;
             }
-            Payload::AddOk | Payload::Cas { .. } | Payload::CasOk => {},
         }
-        self.id += 1;
         Ok(())
     }
+}
 
-    fn handle_event(&mut self, event: Event, output: &mut StdoutLock) -> anyhow::Result<()> {
-        match event { 
-            Event::Eof => {}
-            Event::Read => {
-                self.read_from_store(output)?;
+impl CounterNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::Read => {
+                let queued: usize = self.queue.iter().map(|delta| delta.amount).sum();
+                reply.body.payload = Payload::ReadOk {
+                    value: self.committed + queued + self.totals.values().sum::<usize>(),
+                };
+                reply.send(output)?;
             }
-            Event::Message(msg) => {
-                self.process(msg, output)?;
+            Payload::Add { delta } => {
+                let request = match &self.journal {
+                    None => Some(reply),
+                    Some(journal) => {
+                        replace_file(journal, format!("{}\n", self.accepted + delta).as_bytes())?;
+                        self.accepted += delta;
+                        reply.body.payload = Payload::AddOk;
+                        reply.send(output)?;
+                        None
+                    }
+                };
+                self.queue.push_back(Delta { amount: delta, request, attempts: 0 });
+                self.flush(output)?;
             }
+            Payload::ReadOk { .. } | Payload::AddOk => {},
         }
         Ok(())
     }
 
-    fn read_from_store(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let message = Message {
-            src: self.node_id.clone(),
-            dst: LIN_KV.to_string(),
-            body: Body { 
-                msg_id: None, 
-                in_reply_to: None, 
-                payload: Payload::Read {
-                    key: Some(KEY.to_string())
-                }
-            }
-        };
-        return message.send(output);
+    /// Moves this node's key from the committed sum to one including every queued delta, unless
+    /// a move is in flight or backing off.
+    fn flush(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        if self.flushing.is_some() || self.backing_off || self.queue.is_empty() {
+            return Ok(());
+        }
+        let to = self.committed + self.queue.iter().map(|delta| delta.amount).sum::<usize>();
+        self.flushing = Some(to);
+        self.kv.cas(
+            output,
+            key(&self.node_id),
+            self.committed,
+            to,
+            true,
+            move |result| InjectedPayload::CasResult { to, result },
+        )?;
+        Ok(())
     }
 
-    fn add_to_store(&self, delta: usize, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let message = Message {
-            src: self.node_id.clone(),
-            dst: "lin-kv".to_string(),
-            body: Body { 
-                msg_id: None, 
-                in_reply_to: None, 
-                payload: Payload::Cas { 
-                    key: KEY.to_string(),
-                    from: self.counter, 
-                    to: self.counter + delta,
-                    create_if_not_exists: true
-                }
-            }
-        };
-        return message.send(output);
+    /// Whether `value` is the committed sum plus some prefix of the queue, which is what an
+    /// earlier `cas` of this node would have left.
+    fn is_prefix_sum(&self, value: usize) -> bool {
+        let mut sum = self.committed;
+        sum == value || self.queue.iter().any(|delta| {
+            sum += delta.amount;
+            sum == value
+        })
     }
 
-    fn send_gossip(&self, value: usize, output: &mut StdoutLock) -> anyhow::Result<()> {
-        for neighbor in self.neighbors.iter() {
-            let message = Message {
-                src: self.node_id.clone(),
-                dst: neighbor.clone(),
-                body: Body { 
-                    msg_id: None, 
-                    in_reply_to: None, 
-                    payload: Payload::Gossip { value } 
-                }
-            };
-            message.send(output)?;
+    /// Whether `value` could be what the key held before a restart: part of the journaled total,
+    /// which is the first delta queued while recovering.
+    fn is_recovered_part(&self, value: usize) -> bool {
+        self.recovering && value <= self.committed + self.queue.front().map_or(0, |delta| delta.amount)
+    }
+
+    /// Takes `current`, written before a restart, as committed, leaving the rest of the
+    /// journaled total queued.
+    fn adopt(&mut self, current: usize) {
+        if let Some(recovered) = self.queue.front_mut() {
+            recovered.amount -= current - self.committed;
         }
-        Ok(())
+        self.committed = current;
+        self.recovering = false;
     }
-}
 
-fn main() -> anyhow::Result<()> {
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let (tx, rx) = mpsc::channel();
-
-    let init_msg = wait_for_initialization(&mut stdin.lines(), &mut stdout.lock())
-        .expect("Expected init message");
-    let mut node = Node::from_init(init_msg, tx.clone());
-
-    let main_thread = thread::spawn(move || {
-        let stdin = io::stdin();
-        for line in stdin.lines() {
-            let input = line.expect("no message received");
-            let msg: Message<Payload> = serde_json::from_str(&input)
-                .expect("Error deserializing message");
-            if tx.send(Event::Message(msg)).is_err() {
-                bail!("Error tx send");
+    /// Records that the key holds `to`, answering the deltas it covers.
+    fn commit(&mut self, to: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        while self.queue.front().is_some_and(|delta| self.committed + delta.amount <= to) {
+            let Some(delta) = self.queue.pop_front() else {
+                break;
             };
+            self.committed += delta.amount;
+            if let Some(mut reply) = delta.request {
+                reply.body.payload = Payload::AddOk;
+                reply.send(output)?;
+            }
         }
-        let _ = tx.send(Event::Eof);
         Ok(())
-    });
+    }
 
-    for event in rx {
-        node
-            .handle_event(event, &mut stdout.lock())
-            .expect("node failed processing message");
+    /// Counts a failed attempt against every queued delta and schedules the retry, doubling the
+    /// delay with each failure of the oldest one.
+    fn back_off(&mut self) {
+        for delta in self.queue.iter_mut() {
+            delta.attempts += 1;
+        }
+        let attempts = self.queue.front().map_or(0, |delta| delta.attempts);
+        self.backing_off = true;
+        self.inject.after(retry_delay(attempts), InjectedPayload::Retry);
     }
+}
+
+/// Wait before the retry that follows the `attempts`-th failure.
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_BASE.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(RETRY_MAX)
+}
 
-    main_thread.join().expect("Error running main thread")?;
+fn key(node_id: &str) -> String {
+    format!("counter-{node_id}")
+}
 
-    Ok(())
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, CounterNode, _, _>(AckMode::from_env()?)
 }
 
-fn wait_for_initialization(input: &mut Lines<StdinLock>, output: &mut StdoutLock) -> anyhow::Result<Init> {
-    let msg: Message<InitPayload> = serde_json::from_str(
-        &input
-        .next()
-        .expect("no message received")?)?;
-    let InitPayload::Init(init_msg) = msg.body.payload.clone() else {
-        bail!("Expected init message")
-    };
-    let mut reply = msg.into_reply(Some(0));
-    reply.body.payload = InitPayload::InitOk;
-    reply.send(output)?;
-    Ok(init_msg)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+    use maelstrom::{Error, ErrorCode};
+    use serde_json::{json, Value};
+    use std::fs;
+
+    type Counter = TestNode<CounterNode, AckMode, Payload, InjectedPayload>;
+
+    fn start(ack: AckMode) -> Counter {
+        TestNode::start(ack, "n1", &["n1", "n2"])
+    }
+
+    fn cas_result(counter: &mut Counter, to: usize, result: Result<(), KvError<usize>>) {
+        counter.inject(InjectedPayload::CasResult { to, result });
+    }
+
+    /// `(from, to)` of every `cas` sent, and the requests answered with `add_ok`.
+    fn sent(counter: &mut Counter) -> (Vec<(Value, Value)>, Vec<Value>) {
+        let messages = counter.sent();
+        let cas = messages
+            .iter()
+            .filter(|(_, body)| body["type"] == "cas")
+            .map(|(_, body)| (body["from"].clone(), body["to"].clone()))
+            .collect();
+        let acks = messages
+            .iter()
+            .filter(|(_, body)| body["type"] == "add_ok")
+            .map(|(_, body)| body["in_reply_to"].clone())
+            .collect();
+        (cas, acks)
+    }
+
+    fn timeout() -> KvError<usize> {
+        KvError::Other(Error::new(ErrorCode::Timeout, "timed out"))
+    }
+
+    #[test]
+    fn picks_up_from_a_cas_that_landed_without_a_reply() {
+        let mut counter = start(AckMode::Commit);
+        counter.deliver("c1", 1, Payload::Add { delta: 3 });
+        counter.deliver("c1", 2, Payload::Add { delta: 4 });
+        assert_eq!(sent(&mut counter), (vec![(json!(0), json!(3))], vec![]));
+
+        cas_result(&mut counter, 3, Err(timeout()));
+        counter.inject(InjectedPayload::Retry);
+        assert_eq!(sent(&mut counter), (vec![(json!(0), json!(7))], vec![]));
+
+        // The first write landed after all, so the key holds the first delta only.
+        cas_result(&mut counter, 7, Err(KvError::PreconditionFailed { current: Some(3) }));
+        assert_eq!(sent(&mut counter), (vec![(json!(3), json!(7))], vec![json!(1)]));
+        cas_result(&mut counter, 7, Ok(()));
+        assert_eq!(sent(&mut counter), (vec![], vec![json!(2)]));
+        assert_eq!((counter.node.committed, counter.node.queue.len()), (7, 0));
+    }
+
+    #[test]
+    fn backs_off_when_the_key_holds_no_prefix_sum() {
+        let mut counter = start(AckMode::Commit);
+        counter.deliver("c1", 1, Payload::Add { delta: 3 });
+        counter.deliver("c1", 2, Payload::Add { delta: 4 });
+        sent(&mut counter);
+
+        // Neither 0, 3 nor 7: nothing this node wrote, so nothing is acknowledged.
+        cas_result(&mut counter, 3, Err(KvError::PreconditionFailed { current: Some(5) }));
+        assert_eq!(sent(&mut counter), (vec![], vec![]));
+        assert_eq!(counter.node.committed, 0);
+        assert!(counter.node.backing_off);
+        assert!(counter.node.queue.iter().all(|delta| delta.attempts == 1));
+
+        counter.inject(InjectedPayload::Retry);
+        assert_eq!(sent(&mut counter), (vec![(json!(0), json!(7))], vec![]));
+        cas_result(&mut counter, 7, Err(KvError::PreconditionFailed { current: Some(5) }));
+        assert_eq!(counter.node.queue.front().unwrap().attempts, 2);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let delays: Vec<u64> = (1..=8).map(|attempts| retry_delay(attempts).as_millis() as u64).collect();
+        assert_eq!(delays, [50, 100, 200, 400, 800, 1600, 2000, 2000]);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX);
+    }
+
+    #[test]
+    fn immediate_mode_journals_before_answering_and_recovers_the_rest() {
+        let dir = std::env::temp_dir().join(format!("grow-counter-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let journal = dir.join("grow-counter-n1.total");
+        let _ = fs::remove_file(&journal);
+
+        let mut counter = start(AckMode::Immediate { dir: dir.clone() });
+        counter.deliver("c1", 1, Payload::Add { delta: 6 });
+        counter.deliver("c1", 2, Payload::Add { delta: 4 });
+        assert_eq!(sent(&mut counter), (vec![(json!(0), json!(6))], vec![json!(1), json!(2)]));
+        assert_eq!(fs::read_to_string(&journal).unwrap(), "10\n");
+        cas_result(&mut counter, 6, Ok(()));
+        drop(counter);
+
+        // Restarted before 4 of the 10 reached seq-kv.
+        let mut counter = start(AckMode::Immediate { dir: dir.clone() });
+        counter.inject(InjectedPayload::Tick);
+        assert_eq!(sent(&mut counter).0, vec![(json!(0), json!(10))]);
+        cas_result(&mut counter, 10, Err(KvError::PreconditionFailed { current: Some(6) }));
+        assert_eq!(sent(&mut counter).0, vec![(json!(6), json!(10))]);
+        cas_result(&mut counter, 10, Ok(()));
+        assert_eq!((counter.node.committed, counter.node.queue.len()), (10, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,346 @@
+use maelstrom::{
+    main_loop, Error, ErrorCode, Event, Init, Injector, IntervalSet, KvClient, KvError, KvService, Message, Node,
+    Output,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+const MAX_POLL: usize = 100;
+/// How often records and committed offsets a peer has not acknowledged are sent again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Send { key: String, msg: usize },
+    SendOk { offset: usize },
+    Poll { offsets: HashMap<String, usize> },
+    PollOk { msgs: HashMap<String, Vec<(usize, usize)>> },
+    CommitOffsets { offsets: HashMap<String, usize> },
+    CommitOffsetsOk,
+    ListCommittedOffsets { keys: Vec<String> },
+    ListCommittedOffsetsOk { offsets: HashMap<String, usize> },
+    /// `[offset, msg]` records of each key.
+    Replicate { records: HashMap<String, Vec<(usize, usize)>> },
+    ReplicateOk { offsets: HashMap<String, IntervalSet> },
+    ReplicateCommits { offsets: HashMap<String, usize> },
+    ReplicateCommitsOk { offsets: HashMap<String, usize> },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    OffsetCas {
+        request: Message<Payload>,
+        from: usize,
+        result: Result<(), KvError<usize>>,
+    },
+    Resend,
+}
+
+/// Offsets are claimed with a `cas` on a per-key counter in lin-kv, so every node can accept a
+/// `send`. The node that claimed an offset replicates its record to every peer until they
+/// acknowledge it, and polls only serve the gap-free part of the local log: a poll on a node still
+/// missing an earlier offset returns less, but never skips it.
+struct KafkaNode {
+    peers: Vec<String>,
+    /// `None` on a single node, where offsets are handed out locally.
+    kv: Option<KvClient>,
+    logs: HashMap<String, BTreeMap<usize, usize>>,
+    committed: HashMap<String, usize>,
+    next_offset: HashMap<String, usize>,
+    /// Records each peer has not acknowledged yet, by key and offset.
+    unreplicated: HashMap<String, HashMap<String, BTreeMap<usize, usize>>>,
+    /// Committed offsets each peer has not acknowledged yet.
+    uncommitted: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Node<(), Payload, InjectedPayload> for KafkaNode {
+    fn from_init(_state: (), message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        let peers: Vec<String> = message.node_ids
+            .into_iter()
+            .filter(|n| n != &message.node_id)
+            .collect();
+        if !peers.is_empty() {
+            inject.every(RESEND_INTERVAL, InjectedPayload::Resend);
+        }
+        Ok(KafkaNode {
+            kv: if peers.is_empty() { None } else { Some(KvClient::new(KvService::Lin)) },
+            peers,
+            logs: HashMap::new(),
+            committed: HashMap::new(),
+            next_offset: HashMap::new(),
+            unreplicated: HashMap::new(),
+            uncommitted: HashMap::new(),
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::OffsetCas { request, from, result }) => {
+                let Payload::Send { key, msg } = &request.body.payload else {
+                    return Ok(());
+                };
+                match result {
+                    Ok(()) => {
+                        let (key, msg) = (key.clone(), *msg);
+                        self.next_offset.insert(key.clone(), from + 1);
+                        self.append(key, from, msg, output)?;
+                        let mut reply = request.into_reply(Some(output.next_msg_id()));
+                        reply.body.payload = Payload::SendOk { offset: from };
+                        reply.send(output)?;
+                    }
+                    Err(KvError::PreconditionFailed { current: Some(current) }) => {
+                        self.next_offset.insert(key.clone(), current);
+                        self.allocate_offset(request, output)?;
+                    }
+                    Err(error) => {
+                        let error = match error {
+                            KvError::Other(error) => error,
+                            KvError::KeyNotFound | KvError::PreconditionFailed { .. } => {
+                                Error::new(ErrorCode::TemporarilyUnavailable, format!("could not allocate offset for {key}"))
+                            }
+                        };
+                        if let Some(msg_id) = request.body.msg_id {
+                            output.send_error(&request.src, msg_id, error)?;
+                        }
+                    }
+                }
+            }
+            Event::Injected(InjectedPayload::Resend) => {
+                for (peer, records) in &self.unreplicated {
+                    let records = records
+                        .iter()
+                        .map(|(key, log)| (key.clone(), log.iter().map(|(offset, msg)| (*offset, *msg)).collect()))
+                        .collect();
+                    output.send(peer, Payload::Replicate { records })?;
+                }
+                for (peer, offsets) in &self.uncommitted {
+                    output.send(peer, Payload::ReplicateCommits { offsets: offsets.clone() })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KafkaNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        if let Payload::Send { key, msg: value } = &msg.body.payload {
+            if self.kv.is_none() {
+                let offset = self.next_offset.get(key).copied().unwrap_or(0);
+                self.next_offset.insert(key.clone(), offset + 1);
+                self.append(key.clone(), offset, *value, output)?;
+                let mut reply = msg.into_reply(Some(output.next_msg_id()));
+                reply.body.payload = Payload::SendOk { offset };
+                return reply.send(output);
+            }
+            return self.allocate_offset(msg, output);
+        }
+
+        let src = msg.src.clone();
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::Poll { offsets } => {
+                let msgs = offsets
+                    .into_iter()
+                    .filter_map(|(key, offset)| {
+                        let log = self.logs.get(&key)?;
+                        // Stop at the first offset not replicated here yet.
+                        let records = log
+                            .range(offset..)
+                            .zip(offset..)
+                            .take_while(|((offset, _), expected)| *offset == expected)
+                            .take(MAX_POLL)
+                            .map(|((offset, msg), _)| (*offset, *msg))
+                            .collect();
+                        Some((key, records))
+                    })
+                    .collect();
+                reply.body.payload = Payload::PollOk { msgs };
+                reply.send(output)?;
+            }
+            Payload::CommitOffsets { offsets } => {
+                self.commit(&offsets);
+                for peer in &self.peers {
+                    let pending = self.uncommitted.entry(peer.clone()).or_default();
+                    for (key, offset) in &offsets {
+                        let committed = pending.entry(key.clone()).or_insert(*offset);
+                        *committed = (*committed).max(*offset);
+                    }
+                    output.send(peer, Payload::ReplicateCommits { offsets: offsets.clone() })?;
+                }
+                reply.body.payload = Payload::CommitOffsetsOk;
+                reply.send(output)?;
+            }
+            Payload::ListCommittedOffsets { keys } => {
+                let offsets = keys
+                    .into_iter()
+                    .filter_map(|key| {
+                        let offset = *self.committed.get(&key)?;
+                        Some((key, offset))
+                    })
+                    .collect();
+                reply.body.payload = Payload::ListCommittedOffsetsOk { offsets };
+                reply.send(output)?;
+            }
+            Payload::Replicate { records } => {
+                let mut offsets = HashMap::new();
+                for (key, records) in records {
+                    let log = self.logs.entry(key.clone()).or_default();
+                    log.extend(records.iter().copied());
+                    offsets.insert(key, records.into_iter().map(|(offset, _)| offset).collect());
+                }
+                reply.body.payload = Payload::ReplicateOk { offsets };
+                reply.send(output)?;
+            }
+            Payload::ReplicateOk { offsets } => {
+                if let Some(pending) = self.unreplicated.get_mut(&src) {
+                    for (key, acked) in offsets {
+                        if let Some(log) = pending.get_mut(&key) {
+                            log.retain(|offset, _| !acked.contains(*offset));
+                        }
+                    }
+                    pending.retain(|_, log| !log.is_empty());
+                }
+                self.unreplicated.retain(|_, pending| !pending.is_empty());
+            }
+            Payload::ReplicateCommits { offsets } => {
+                self.commit(&offsets);
+                reply.body.payload = Payload::ReplicateCommitsOk { offsets };
+                reply.send(output)?;
+            }
+            Payload::ReplicateCommitsOk { offsets } => {
+                if let Some(pending) = self.uncommitted.get_mut(&src) {
+                    pending.retain(|key, offset| offsets.get(key).is_none_or(|acked| acked < offset));
+                }
+                self.uncommitted.retain(|_, pending| !pending.is_empty());
+            }
+            Payload::Send { .. } => unreachable!("send is handled above"),
+            Payload::SendOk { .. }
+            | Payload::PollOk { .. }
+            | Payload::CommitOffsetsOk
+            | Payload::ListCommittedOffsetsOk { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Claims the next offset of the request's key with a `cas` on its counter in lin-kv.
+    fn allocate_offset(&mut self, request: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Payload::Send { key, .. } = &request.body.payload else {
+            return Ok(());
+        };
+        let kv = self.kv.expect("offsets are only allocated through lin-kv on multiple nodes");
+        let from = self.next_offset.get(key).copied().unwrap_or(0);
+        let counter = format!("offset_{key}");
+        kv.cas(output, counter, from, from + 1, true, move |result| {
+            InjectedPayload::OffsetCas { request, from, result }
+        })?;
+        Ok(())
+    }
+
+    /// Appends a record to the local log and replicates it to every peer until acknowledged.
+    fn append(&mut self, key: String, offset: usize, msg: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        for peer in &self.peers {
+            let pending = self.unreplicated.entry(peer.clone()).or_default();
+            pending.entry(key.clone()).or_default().insert(offset, msg);
+            let records = HashMap::from([(key.clone(), vec![(offset, msg)])]);
+            output.send(peer, Payload::Replicate { records })?;
+        }
+        self.logs.entry(key).or_default().insert(offset, msg);
+        Ok(())
+    }
+
+    fn commit(&mut self, offsets: &HashMap<String, usize>) {
+        for (key, offset) in offsets {
+            let committed = self.committed.entry(key.clone()).or_insert(*offset);
+            *committed = (*committed).max(*offset);
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, KafkaNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::{request, TestNode};
+    use serde_json::{json, Value};
+
+    type Kafka = TestNode<KafkaNode, (), Payload, InjectedPayload>;
+
+    fn start(node_id: &str) -> Kafka {
+        TestNode::start((), node_id, &["n1", "n2"])
+    }
+
+    /// Answers the `cas` claiming `from` for a `send` of `msg` to `k`.
+    fn offset_cas(kafka: &mut Kafka, msg: usize, from: usize, result: Result<(), KvError<usize>>) {
+        let request = request("c1", "n1", 1, Payload::Send { key: "k".to_string(), msg });
+        kafka.inject(InjectedPayload::OffsetCas { request, from, result });
+    }
+
+    fn poll(kafka: &mut Kafka) -> Value {
+        kafka.deliver("c1", 1, Payload::Poll { offsets: HashMap::from([("k".to_string(), 0)]) });
+        kafka.sent().pop().unwrap().1["msgs"]["k"].clone()
+    }
+
+    #[test]
+    fn polls_stop_at_the_first_offset_not_replicated_yet() {
+        let mut kafka = start("n2");
+        let records = HashMap::from([("k".to_string(), vec![(0, 10), (2, 12)])]);
+        kafka.deliver("n1", 1, Payload::Replicate { records });
+        assert_eq!(kafka.sent()[0].1["offsets"], json!({"k": [[0, 0], [2, 2]]}));
+        assert_eq!(poll(&mut kafka), json!([[0, 10]]));
+
+        let records = HashMap::from([("k".to_string(), vec![(1, 11)])]);
+        kafka.deliver("n1", 2, Payload::Replicate { records });
+        kafka.sent();
+        assert_eq!(poll(&mut kafka), json!([[0, 10], [1, 11], [2, 12]]));
+    }
+
+    #[test]
+    fn claimed_offsets_are_resent_until_acknowledged() {
+        let mut kafka = start("n1");
+        kafka.deliver("c1", 1, Payload::Send { key: "k".to_string(), msg: 5 });
+        let cas = kafka.sent();
+        assert_eq!(cas[0].0, "lin-kv");
+        assert_eq!((&cas[0].1["key"], &cas[0].1["from"], &cas[0].1["to"]), (&json!("offset_k"), &json!(0), &json!(1)));
+        offset_cas(&mut kafka, 5, 0, Ok(()));
+
+        // Another node claimed 1 and 2 meanwhile, so the retry starts from the counter's value.
+        kafka.deliver("c1", 2, Payload::Send { key: "k".to_string(), msg: 6 });
+        offset_cas(&mut kafka, 6, 1, Err(KvError::PreconditionFailed { current: Some(3) }));
+        let retried = kafka.sent().into_iter().rfind(|(_, body)| body["type"] == "cas").unwrap();
+        assert_eq!((&retried.1["from"], &retried.1["to"]), (&json!(3), &json!(4)));
+        offset_cas(&mut kafka, 6, 3, Ok(()));
+        let offsets: Vec<Value> = kafka
+            .sent()
+            .into_iter()
+            .filter(|(_, body)| body["type"] == "send_ok")
+            .map(|(_, body)| body["offset"].clone())
+            .collect();
+        assert_eq!(offsets, [json!(3)]);
+
+        kafka.inject(InjectedPayload::Resend);
+        let resent = kafka.sent();
+        assert_eq!((resent.len(), resent[0].0.as_str()), (1, "n2"));
+        assert_eq!(resent[0].1["records"], json!({"k": [[0, 5], [3, 6]]}));
+
+        let acked = HashMap::from([("k".to_string(), IntervalSet::from_iter([0]))]);
+        kafka.deliver("n2", 3, Payload::ReplicateOk { offsets: acked });
+        kafka.inject(InjectedPayload::Resend);
+        assert_eq!(kafka.sent()[0].1["records"], json!({"k": [[3, 6]]}));
+
+        let acked = HashMap::from([("k".to_string(), IntervalSet::from_iter([3]))]);
+        kafka.deliver("n2", 4, Payload::ReplicateOk { offsets: acked });
+        kafka.inject(InjectedPayload::Resend);
+        assert!(kafka.sent().is_empty());
+    }
+}
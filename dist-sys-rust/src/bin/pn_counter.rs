@@ -0,0 +1,176 @@
+use maelstrom::{main_loop, Event, Init, Injector, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+    Replicate { counter: PnCounter },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Gossip,
+}
+
+/// Grow-only counter: one monotonic total per node, merged by pointwise max.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    fn increment(&mut self, node: &str, amount: u64) {
+        *self.0.entry(node.to_string()).or_insert(0) += amount;
+    }
+
+    fn merge(&mut self, other: &GCounter) {
+        for (node, total) in &other.0 {
+            let mine = self.0.entry(node.clone()).or_insert(0);
+            *mine = (*mine).max(*total);
+        }
+    }
+
+    fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+/// Counter accepting negative deltas: increments and decrements are kept in two G-counters.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PnCounter {
+    inc: GCounter,
+    dec: GCounter,
+}
+
+impl PnCounter {
+    fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.inc.increment(node, delta.unsigned_abs());
+        } else {
+            self.dec.increment(node, delta.unsigned_abs());
+        }
+    }
+
+    fn merge(&mut self, other: &PnCounter) {
+        self.inc.merge(&other.inc);
+        self.dec.merge(&other.dec);
+    }
+
+    fn value(&self) -> i64 {
+        self.inc.value() as i64 - self.dec.value() as i64
+    }
+}
+
+struct PnCounterNode {
+    node_id: String,
+    peers: Vec<String>,
+    counter: PnCounter,
+}
+
+impl Node<(), Payload, InjectedPayload> for PnCounterNode {
+    fn from_init(_state: (), message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        inject.every(Duration::from_millis(300), InjectedPayload::Gossip);
+        Ok(PnCounterNode {
+            peers: message.node_ids
+                .into_iter()
+                .filter(|n| *n != message.node_id)
+                .collect(),
+            node_id: message.node_id,
+            counter: PnCounter::default(),
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Gossip) => {
+                for peer in &self.peers {
+                    output.send(peer, Payload::Replicate { counter: self.counter.clone() })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PnCounterNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        if let Payload::Replicate { counter } = &msg.body.payload {
+            self.counter.merge(counter);
+            return Ok(());
+        }
+
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::Add { delta } => {
+                self.counter.add(&self.node_id, delta);
+                reply.body.payload = Payload::AddOk;
+                reply.send(output)?;
+            }
+            Payload::Read => {
+                reply.body.payload = Payload::ReadOk { value: self.counter.value() };
+                reply.send(output)?;
+            }
+            Payload::Replicate { .. } => unreachable!("replicate is handled above"),
+            Payload::AddOk | Payload::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, PnCounterNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+
+    fn counter(adds: &[(&str, i64)]) -> PnCounter {
+        let mut counter = PnCounter::default();
+        for (node, delta) in adds {
+            counter.add(node, *delta);
+        }
+        counter
+    }
+
+    #[test]
+    fn merge_is_commutative_and_idempotent() {
+        let a = counter(&[("n1", 5), ("n1", -2), ("n2", 1)]);
+        let b = counter(&[("n2", 4), ("n3", -7)]);
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!((ab.inc.0.clone(), ab.dec.0.clone()), (ba.inc.0.clone(), ba.dec.0.clone()));
+        // n2's total of 4 already covers the 1 that `a` holds for it.
+        assert_eq!(ab.value(), 5 - 2 + 4 - 7);
+
+        let before = ab.clone();
+        ab.merge(&a);
+        ab.merge(&ab.clone());
+        assert_eq!((ab.inc.0, ab.dec.0), (before.inc.0, before.dec.0));
+    }
+
+    #[test]
+    fn replicated_counters_merge_into_a_negative_total_without_a_reply() {
+        let mut n1: TestNode<PnCounterNode, (), Payload, InjectedPayload> = TestNode::start((), "n1", &["n1", "n2"]);
+        n1.deliver("c1", 1, Payload::Add { delta: 3 });
+        n1.deliver("c1", 2, Payload::Add { delta: -10 });
+        n1.sent();
+
+        n1.deliver("n2", 1, Payload::Replicate { counter: counter(&[("n2", -4), ("n2", 2)]) });
+        assert!(n1.sent().is_empty());
+        n1.deliver("c1", 3, Payload::Read);
+        assert_eq!(n1.sent()[0].1["value"], -9);
+    }
+}
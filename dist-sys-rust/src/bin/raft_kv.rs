@@ -0,0 +1,560 @@
+use maelstrom::{main_loop, random_below, Error, ErrorCode, Event, Init, Injector, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How often timers are checked and the log is pushed to followers.
+const TICK: Duration = Duration::from_millis(50);
+/// Base time without hearing from a leader before calling an election; the actual deadline adds up to as much again.
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest a leader stays silent towards a follower that is up to date.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a forwarded client request waits for the leader's answer.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Read { key: u64 },
+    ReadOk { value: u64 },
+    Write { key: u64, value: u64 },
+    WriteOk,
+    Cas { key: u64, from: u64, to: u64 },
+    CasOk,
+    RequestVote { term: u64, candidate_id: String, last_log_index: usize, last_log_term: u64 },
+    RequestVoteOk { term: u64, vote_granted: bool },
+    AppendEntries {
+        term: u64,
+        leader_id: String,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: usize,
+    },
+    AppendEntriesOk { term: u64, success: bool },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Tick,
+    Vote { peer: String, term: u64, result: Result<Payload, Error> },
+    Append { peer: String, term: u64, next_index: usize, sent: usize, result: Result<Payload, Error> },
+    Forwarded { request: Message<Payload>, result: Result<Payload, Error> },
+}
+
+/// A log entry: the client request it carries, tagged with the term of the leader that received it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    term: u64,
+    op: Message<Payload>,
+}
+
+/// The replicated log, indexed from 1; index 0 stands for the empty prefix with term 0.
+#[derive(Debug, Default)]
+struct Log {
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    fn get(&self, index: usize) -> &Entry {
+        &self.entries[index - 1]
+    }
+
+    fn term(&self, index: usize) -> u64 {
+        if index == 0 { 0 } else { self.get(index).term }
+    }
+
+    fn last_index(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term(self.last_index())
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    /// Drops every entry after `index`.
+    fn truncate(&mut self, index: usize) {
+        self.entries.truncate(index);
+    }
+
+    fn entries_from(&self, index: usize) -> Vec<Entry> {
+        self.entries[index - 1..].to_vec()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct RaftNode {
+    node_id: String,
+    peers: Vec<String>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    votes: HashSet<String>,
+    log: Log,
+    commit_index: usize,
+    last_applied: usize,
+    store: HashMap<u64, u64>,
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    election_deadline: Instant,
+    step_down_deadline: Instant,
+    last_replication: Instant,
+}
+
+impl Node<(), Payload, InjectedPayload> for RaftNode {
+    fn from_init(_state: (), message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        inject.every(TICK, InjectedPayload::Tick);
+        let now = Instant::now();
+        Ok(RaftNode {
+            peers: message.node_ids
+                .into_iter()
+                .filter(|n| *n != message.node_id)
+                .collect(),
+            node_id: message.node_id,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            votes: HashSet::new(),
+            log: Log::default(),
+            commit_index: 0,
+            last_applied: 0,
+            store: HashMap::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: now + ELECTION_TIMEOUT + jitter(ELECTION_TIMEOUT),
+            step_down_deadline: now + ELECTION_TIMEOUT,
+            last_replication: now,
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Tick) => {
+                let now = Instant::now();
+                match self.role {
+                    Role::Leader if !self.peers.is_empty() && now > self.step_down_deadline => {
+                        self.become_follower();
+                    }
+                    Role::Leader => self.replicate(output)?,
+                    Role::Follower | Role::Candidate if now > self.election_deadline => {
+                        self.become_candidate(output)?;
+                    }
+                    Role::Follower | Role::Candidate => {}
+                }
+            }
+            Event::Injected(InjectedPayload::Vote { peer, term, result }) => {
+                let Ok(Payload::RequestVoteOk { term: remote_term, vote_granted }) = result else {
+                    return Ok(());
+                };
+                self.maybe_step_down(remote_term);
+                if self.role == Role::Candidate && self.term == term && vote_granted {
+                    self.votes.insert(peer);
+                    if self.votes.len() >= self.majority() {
+                        self.become_leader(output)?;
+                    }
+                }
+            }
+            Event::Injected(InjectedPayload::Append { peer, term, next_index, sent, result }) => {
+                let Ok(Payload::AppendEntriesOk { term: remote_term, success }) = result else {
+                    return Ok(());
+                };
+                self.maybe_step_down(remote_term);
+                if self.role != Role::Leader || self.term != term {
+                    return Ok(());
+                }
+                self.reset_step_down_deadline();
+                if success {
+                    let next = self.next_index.entry(peer.clone()).or_insert(next_index + sent);
+                    *next = (*next).max(next_index + sent);
+                    let matched = self.match_index.entry(peer).or_insert(0);
+                    *matched = (*matched).max(next_index + sent - 1);
+                    self.advance_commit_index(output)?;
+                } else if let Some(next) = self.next_index.get_mut(&peer) {
+                    *next = next.saturating_sub(1).max(1);
+                }
+            }
+            Event::Injected(InjectedPayload::Forwarded { request, result }) => {
+                respond(request, result, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RaftNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match &msg.body.payload {
+            Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {
+                return self.submit(msg, output);
+            }
+            Payload::RequestVote { .. } | Payload::AppendEntries { .. } => {}
+            Payload::ReadOk { .. }
+            | Payload::WriteOk
+            | Payload::CasOk
+            | Payload::RequestVoteOk { .. }
+            | Payload::AppendEntriesOk { .. } => return Ok(()),
+        }
+
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::RequestVote { term, candidate_id, last_log_index, last_log_term } => {
+                self.maybe_step_down(term);
+                let up_to_date = (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+                let vote_granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_ref().is_none_or(|voted| *voted == candidate_id);
+                if vote_granted {
+                    self.voted_for = Some(candidate_id);
+                    self.reset_election_deadline();
+                }
+                reply.body.payload = Payload::RequestVoteOk { term: self.term, vote_granted };
+                reply.send(output)?;
+            }
+            Payload::AppendEntries { term, leader_id, prev_log_index, prev_log_term, entries, leader_commit } => {
+                self.maybe_step_down(term);
+                let mut success = false;
+                if term == self.term {
+                    if self.role == Role::Candidate {
+                        self.become_follower();
+                    }
+                    self.leader = Some(leader_id);
+                    self.reset_election_deadline();
+                    if prev_log_index <= self.log.last_index() && self.log.term(prev_log_index) == prev_log_term {
+                        self.merge(prev_log_index, entries);
+                        if leader_commit > self.commit_index {
+                            self.commit_index = leader_commit.min(self.log.last_index());
+                            self.advance_state_machine(output)?;
+                        }
+                        success = true;
+                    }
+                }
+                reply.body.payload = Payload::AppendEntriesOk { term: self.term, success };
+                reply.send(output)?;
+            }
+            _ => unreachable!("only raft requests are answered here"),
+        }
+        Ok(())
+    }
+
+    /// Appends a client request to the log if we lead, forwards it to the leader otherwise.
+    fn submit(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        if self.role == Role::Leader {
+            self.log.push(Entry { term: self.term, op: msg });
+            return self.advance_commit_index(output);
+        }
+        let Some(leader) = self.leader.clone() else {
+            return Err(Error::new(ErrorCode::TemporarilyUnavailable, "not a leader").into());
+        };
+        output.call(&leader, msg.body.payload.clone(), FORWARD_TIMEOUT, move |result| {
+            InjectedPayload::Forwarded { request: msg, result }
+        })?;
+        Ok(())
+    }
+
+    /// Stores `entries` after `prev_log_index`, dropping our suffix from the first conflicting term on.
+    fn merge(&mut self, prev_log_index: usize, entries: Vec<Entry>) {
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev_log_index + 1 + offset;
+            if index <= self.log.last_index() {
+                if self.log.term(index) == entry.term {
+                    continue;
+                }
+                self.log.truncate(index - 1);
+            }
+            self.log.push(entry);
+        }
+    }
+
+    fn replicate(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let heartbeat = self.last_replication.elapsed() >= HEARTBEAT_INTERVAL;
+        let mut replicated = false;
+        for peer in &self.peers {
+            let next_index = self.next_index[peer];
+            let entries = self.log.entries_from(next_index);
+            if entries.is_empty() && !heartbeat {
+                continue;
+            }
+            replicated = true;
+            let (peer, term, sent) = (peer.clone(), self.term, entries.len());
+            let request = Payload::AppendEntries {
+                term,
+                leader_id: self.node_id.clone(),
+                prev_log_index: next_index - 1,
+                prev_log_term: self.log.term(next_index - 1),
+                entries,
+                leader_commit: self.commit_index,
+            };
+            output.call(&peer.clone(), request, HEARTBEAT_INTERVAL, move |result| {
+                InjectedPayload::Append { peer, term, next_index, sent, result }
+            })?;
+        }
+        if replicated {
+            self.last_replication = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Commits the highest index stored on a majority, provided it was written in our term.
+    fn advance_commit_index(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let mut matched: Vec<usize> = self.peers
+            .iter()
+            .map(|peer| self.match_index[peer])
+            .collect();
+        matched.push(self.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.majority() - 1];
+        if index > self.commit_index && self.log.term(index) == self.term {
+            self.commit_index = index;
+            self.advance_state_machine(output)?;
+        }
+        Ok(())
+    }
+
+    /// Applies committed entries; the leader also answers the clients that submitted them.
+    fn advance_state_machine(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let op = self.log.get(self.last_applied).op.clone();
+            let result = self.apply(&op.body.payload);
+            if self.role == Role::Leader {
+                respond(op, result, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: &Payload) -> Result<Payload, Error> {
+        match *op {
+            Payload::Read { key } => {
+                let value = *self.store.get(&key).ok_or_else(key_not_found)?;
+                Ok(Payload::ReadOk { value })
+            }
+            Payload::Write { key, value } => {
+                self.store.insert(key, value);
+                Ok(Payload::WriteOk)
+            }
+            Payload::Cas { key, from, to } => {
+                let current = self.store.get_mut(&key).ok_or_else(key_not_found)?;
+                if *current != from {
+                    return Err(Error::new(
+                        ErrorCode::PreconditionFailed,
+                        format!("current value {current} is not {from}"),
+                    ));
+                }
+                *current = to;
+                Ok(Payload::CasOk)
+            }
+            _ => Err(Error::new(ErrorCode::NotSupported, "not a key/value operation")),
+        }
+    }
+
+    fn become_follower(&mut self) {
+        self.role = Role::Follower;
+        self.leader = None;
+        self.next_index.clear();
+        self.match_index.clear();
+        self.reset_election_deadline();
+    }
+
+    fn become_candidate(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        self.role = Role::Candidate;
+        self.advance_term(self.term + 1);
+        self.voted_for = Some(self.node_id.clone());
+        self.leader = None;
+        self.votes = HashSet::from([self.node_id.clone()]);
+        self.reset_election_deadline();
+
+        if self.votes.len() >= self.majority() {
+            return self.become_leader(output);
+        }
+        let term = self.term;
+        for peer in &self.peers {
+            let request = Payload::RequestVote {
+                term,
+                candidate_id: self.node_id.clone(),
+                last_log_index: self.log.last_index(),
+                last_log_term: self.log.last_term(),
+            };
+            let peer = peer.clone();
+            output.call(&peer.clone(), request, ELECTION_TIMEOUT, move |result| {
+                InjectedPayload::Vote { peer, term, result }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.node_id.clone());
+        self.next_index = self.peers
+            .iter()
+            .map(|peer| (peer.clone(), self.log.last_index() + 1))
+            .collect();
+        self.match_index = self.peers
+            .iter()
+            .map(|peer| (peer.clone(), 0))
+            .collect();
+        self.reset_step_down_deadline();
+        self.last_replication = Instant::now() - HEARTBEAT_INTERVAL;
+        self.replicate(output)
+    }
+
+    fn advance_term(&mut self, term: u64) {
+        self.term = term;
+        self.voted_for = None;
+    }
+
+    /// Any newer term seen from a peer makes us a follower of that term.
+    fn maybe_step_down(&mut self, remote_term: u64) {
+        if remote_term > self.term {
+            self.advance_term(remote_term);
+            self.become_follower();
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self) {
+        self.election_deadline = Instant::now() + ELECTION_TIMEOUT + jitter(ELECTION_TIMEOUT);
+    }
+
+    fn reset_step_down_deadline(&mut self) {
+        self.step_down_deadline = Instant::now() + ELECTION_TIMEOUT;
+    }
+}
+
+/// Answers the client behind `request` with the outcome of its operation.
+fn respond(request: Message<Payload>, result: Result<Payload, Error>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+    let Some(msg_id) = request.body.msg_id else {
+        return Ok(());
+    };
+    match result {
+        Ok(payload) => {
+            let mut reply = request.into_reply(Some(output.next_msg_id()));
+            reply.body.payload = payload;
+            reply.send(output)
+        }
+        Err(error) => output.send_error(&request.src, msg_id, error),
+    }
+}
+
+fn key_not_found() -> Error {
+    Error::new(ErrorCode::KeyDoesNotExist, "key does not exist")
+}
+
+/// A random duration below `max`.
+fn jitter(max: Duration) -> Duration {
+    Duration::from_millis(random_below(max.as_millis() as usize) as u64)
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, RaftNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::{request, TestNode};
+
+    type Raft = TestNode<RaftNode, (), Payload, InjectedPayload>;
+
+    fn start(node_id: &str) -> Raft {
+        TestNode::start((), node_id, &["n1", "n2", "n3"])
+    }
+
+    fn write(msg_id: usize, key: u64, value: u64) -> Message<Payload> {
+        request("c1", "n1", msg_id, Payload::Write { key, value })
+    }
+
+    fn append(term: u64, prev_log_index: usize, prev_log_term: u64, entries: Vec<Entry>) -> Event<Payload, InjectedPayload> {
+        let payload = Payload::AppendEntries {
+            term,
+            leader_id: "n1".to_string(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: 0,
+        };
+        Event::Message(request("n1", "n2", 1, payload))
+    }
+
+    fn log_terms(node: &RaftNode) -> Vec<u64> {
+        node.log.entries.iter().map(|entry| entry.term).collect()
+    }
+
+    #[test]
+    fn follower_truncates_entries_that_conflict_with_the_leader() {
+        let mut raft = start("n2");
+        let entries = vec![
+            Entry { term: 1, op: write(1, 1, 1) },
+            Entry { term: 1, op: write(2, 2, 2) },
+            Entry { term: 1, op: write(3, 3, 3) },
+        ];
+        raft.step(append(1, 0, 0, entries));
+        assert_eq!(log_terms(&raft.node), [1, 1, 1]);
+
+        // A stale copy of an entry we already have changes nothing.
+        raft.step(append(1, 0, 0, vec![Entry { term: 1, op: write(1, 1, 1) }]));
+        assert_eq!(log_terms(&raft.node), [1, 1, 1]);
+
+        // A new leader overwrote index 2 in term 3: our entries from there on go.
+        raft.step(append(3, 1, 1, vec![Entry { term: 3, op: write(9, 9, 9) }]));
+        assert_eq!(log_terms(&raft.node), [1, 3]);
+        assert!(matches!(raft.node.log.get(2).op.body.payload, Payload::Write { key: 9, .. }));
+
+        // No entry at index 5 to match, so nothing is appended.
+        raft.step(append(3, 5, 3, vec![Entry { term: 3, op: write(10, 10, 10) }]));
+        assert_eq!(log_terms(&raft.node), [1, 3]);
+        let successes: Vec<bool> = raft.sent().iter().map(|(_, body)| body["success"].as_bool().unwrap()).collect();
+        assert_eq!(successes, [true, true, true, false]);
+    }
+
+    #[test]
+    fn leader_commits_an_earlier_term_entry_only_behind_one_of_its_own() {
+        let mut raft = start("n1");
+        raft.node.log.push(Entry { term: 1, op: write(1, 1, 1) });
+        raft.node.term = 2;
+        raft.node.become_leader(&mut raft.output).unwrap();
+        let acked = |next_index, sent| {
+            let result = Ok(Payload::AppendEntriesOk { term: 2, success: true });
+            Event::Injected(InjectedPayload::Append { peer: "n2".to_string(), term: 2, next_index, sent, result })
+        };
+
+        // Stored on a majority, but written in term 1.
+        raft.step(acked(1, 1));
+        assert_eq!(raft.node.commit_index, 0);
+
+        raft.step(Event::Message(write(2, 2, 2)));
+        assert_eq!(raft.node.commit_index, 0);
+        raft.step(acked(2, 1));
+        assert_eq!((raft.node.commit_index, raft.node.last_applied), (2, 2));
+        assert_eq!(raft.node.store, HashMap::from([(1, 1), (2, 2)]));
+        let answered: Vec<_> = raft
+            .sent()
+            .into_iter()
+            .filter(|(_, body)| body["type"] == "write_ok")
+            .map(|(_, body)| body["in_reply_to"].clone())
+            .collect();
+        assert_eq!(answered, [1, 2]);
+    }
+}
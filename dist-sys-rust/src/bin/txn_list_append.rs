@@ -0,0 +1,388 @@
+use maelstrom::{main_loop, Error, ErrorCode, Event, Init, Injector, KvClient, KvError, KvService, Message, Node, Output, XorShift};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// lin-kv key holding the id of the current map thunk.
+const ROOT: &str = "root";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "append")]
+    Append,
+}
+
+/// A micro-op: `["r", key, null]` or `["append", key, element]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Op(OpKind, u64, Value);
+
+/// Value of a map thunk: each key paired with the id of the list thunk holding its value.
+type KeyMap = Vec<(u64, String)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    RootRead { txn: usize, result: Result<String, KvError<String>> },
+    MapRead { txn: usize, id: String, result: Result<KeyMap, KvError<KeyMap>> },
+    ListRead { txn: usize, id: String, result: Result<Vec<Value>, KvError<Vec<Value>>> },
+    Saved { txn: usize, result: Result<(), Error> },
+    RootCas { txn: usize, result: Result<(), KvError<String>> },
+}
+
+/// A transaction waiting on the storage service.
+struct Pending {
+    request: Message<Payload>,
+    txn: Vec<Op>,
+    /// Map thunk id the transaction started from, `None` while the root does not exist.
+    root: Option<String>,
+    map: BTreeMap<u64, String>,
+    /// Map thunk id the transaction wrote, once it has any appends.
+    written: Option<String>,
+    /// Replies still expected before the next phase can start.
+    outstanding: usize,
+}
+
+struct ListAppendNode {
+    kv: KvClient,
+    next_txn: usize,
+    /// Node id plus a number drawn at startup: a restarted node counts thunks from 0 again, and
+    /// must not overwrite the thunks its earlier run left in lin-kv.
+    thunk_prefix: String,
+    next_thunk: usize,
+    pending: HashMap<usize, Pending>,
+    /// Thunks never change once written, so any value read or saved can be reused.
+    maps: HashMap<String, KeyMap>,
+    lists: HashMap<String, Vec<Value>>,
+}
+
+impl Node<(), Payload, InjectedPayload> for ListAppendNode {
+    fn from_init(_state: (), message: Init, _inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        Ok(ListAppendNode {
+            kv: KvClient::new(KvService::Lin),
+            next_txn: 0,
+            thunk_prefix: format!("{}-{:x}", message.node_id, XorShift::from_entropy().next_u64()),
+            next_thunk: 0,
+            pending: HashMap::new(),
+            maps: HashMap::new(),
+            lists: HashMap::new(),
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::RootRead { txn, result }) => {
+                let root = match result {
+                    Ok(root) => Some(root),
+                    Err(KvError::KeyNotFound) => None,
+                    Err(error) => return self.abort(txn, unavailable(error), output),
+                };
+                if let Some(pending) = self.pending.get_mut(&txn) {
+                    pending.root = root.clone();
+                }
+                match root {
+                    Some(id) => self.load_map(txn, id, output)?,
+                    None => self.load_lists(txn, output)?,
+                }
+            }
+            Event::Injected(InjectedPayload::MapRead { txn, id, result }) => {
+                match result {
+                    Ok(map) => {
+                        self.maps.insert(id.clone(), map);
+                        self.load_map(txn, id, output)?;
+                    }
+                    Err(error) => self.abort(txn, unavailable(error), output)?,
+                }
+            }
+            Event::Injected(InjectedPayload::ListRead { txn, id, result }) => {
+                match result {
+                    Ok(list) => {
+                        self.lists.insert(id, list);
+                        if self.settle(txn) {
+                            self.execute(txn, output)?;
+                        }
+                    }
+                    Err(error) => self.abort(txn, unavailable(error), output)?,
+                }
+            }
+            Event::Injected(InjectedPayload::Saved { txn, result }) => {
+                match result {
+                    Ok(()) => {
+                        if self.settle(txn) {
+                            self.commit(txn, output)?;
+                        }
+                    }
+                    Err(error) => self.abort(txn, error, output)?,
+                }
+            }
+            Event::Injected(InjectedPayload::RootCas { txn, result }) => {
+                match result {
+                    Ok(()) => self.finish(txn, output)?,
+                    Err(KvError::PreconditionFailed { .. }) => {
+                        let conflict = Error::new(ErrorCode::TxnConflict, "root changed during the transaction");
+                        self.abort(txn, conflict, output)?;
+                    }
+                    Err(KvError::KeyNotFound) => {
+                        self.abort(txn, Error::new(ErrorCode::TxnConflict, "root disappeared"), output)?;
+                    }
+                    Err(KvError::Other(error)) => self.abort(txn, error, output)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ListAppendNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Payload::Txn { txn } = &msg.body.payload else {
+            return Ok(());
+        };
+        let id = self.next_txn;
+        self.next_txn += 1;
+        self.pending.insert(id, Pending {
+            txn: txn.clone(),
+            request: msg,
+            root: None,
+            map: BTreeMap::new(),
+            written: None,
+            outstanding: 0,
+        });
+        self.kv.read(output, ROOT, move |result| InjectedPayload::RootRead { txn: id, result })?;
+        Ok(())
+    }
+
+    /// Installs map thunk `id` into the transaction, fetching it first unless cached.
+    fn load_map(&mut self, txn: usize, id: String, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(map) = self.maps.get(&id) else {
+            self.kv.read(output, id.clone(), move |result| InjectedPayload::MapRead { txn, id, result })?;
+            return Ok(());
+        };
+        if let Some(pending) = self.pending.get_mut(&txn) {
+            pending.map = map.iter().cloned().collect();
+        }
+        self.load_lists(txn, output)
+    }
+
+    /// Fetches the uncached list thunks of every key the transaction touches.
+    fn load_lists(&mut self, txn: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.get_mut(&txn) else {
+            return Ok(());
+        };
+        let missing: HashSet<String> = pending.txn
+            .iter()
+            .filter_map(|Op(_, key, _)| pending.map.get(key))
+            .filter(|id| !self.lists.contains_key(*id))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return self.execute(txn, output);
+        }
+        pending.outstanding = missing.len();
+        for id in missing {
+            self.kv.read(output, id.clone(), move |result| InjectedPayload::ListRead { txn, id, result })?;
+        }
+        Ok(())
+    }
+
+    /// Counts one reply against the transaction; true once none is outstanding.
+    fn settle(&mut self, txn: usize) -> bool {
+        let Some(pending) = self.pending.get_mut(&txn) else {
+            return false;
+        };
+        pending.outstanding -= 1;
+        pending.outstanding == 0
+    }
+
+    /// Runs the transaction over the loaded lists, then saves the new thunks it produced.
+    fn execute(&mut self, txn: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.get_mut(&txn) else {
+            return Ok(());
+        };
+        let mut written: BTreeMap<u64, Vec<Value>> = BTreeMap::new();
+        for Op(kind, key, value) in pending.txn.iter_mut() {
+            let current = written
+                .get(key)
+                .or_else(|| pending.map.get(key).and_then(|id| self.lists.get(id)));
+            match kind {
+                OpKind::Read => {
+                    *value = current.map_or(Value::Null, |list| Value::from(list.clone()));
+                }
+                OpKind::Append => {
+                    let mut list = current.cloned().unwrap_or_default();
+                    list.push(value.clone());
+                    written.insert(*key, list);
+                }
+            }
+        }
+        if written.is_empty() {
+            return self.finish(txn, output);
+        }
+
+        let mut saves: Vec<(String, Value)> = Vec::new();
+        for (key, list) in written {
+            let id = format!("{}-{}", self.thunk_prefix, self.next_thunk);
+            self.next_thunk += 1;
+            pending.map.insert(key, id.clone());
+            saves.push((id.clone(), Value::from(list.clone())));
+            self.lists.insert(id, list);
+        }
+        let map_id = format!("{}-{}", self.thunk_prefix, self.next_thunk);
+        self.next_thunk += 1;
+        let map: KeyMap = pending.map.iter().map(|(key, id)| (*key, id.clone())).collect();
+        saves.push((map_id.clone(), serde_json::to_value(&map)?));
+        self.maps.insert(map_id.clone(), map);
+        pending.written = Some(map_id);
+        pending.outstanding = saves.len();
+
+        for (id, value) in saves {
+            self.kv.write(output, id, value, move |result| InjectedPayload::Saved {
+                txn,
+                result: result.map_err(unavailable),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Swings the root from the map the transaction read to the one it wrote.
+    fn commit(&mut self, txn: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.get(&txn) else {
+            return Ok(());
+        };
+        let Some(map_id) = pending.written.clone() else {
+            return Ok(());
+        };
+        let from = pending.root.clone().unwrap_or_default();
+        let create = pending.root.is_none();
+        self.kv.cas(output, ROOT, from, map_id, create, move |result| InjectedPayload::RootCas { txn, result })?;
+        Ok(())
+    }
+
+    fn finish(&mut self, txn: usize, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.remove(&txn) else {
+            return Ok(());
+        };
+        let mut reply = pending.request.into_reply(Some(output.next_msg_id()));
+        reply.body.payload = Payload::TxnOk { txn: pending.txn };
+        reply.send(output)
+    }
+
+    fn abort(&mut self, txn: usize, error: Error, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.remove(&txn) else {
+            return Ok(());
+        };
+        match pending.request.body.msg_id {
+            Some(msg_id) => output.send_error(&pending.request.src, msg_id, error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Storage failures before the root is swapped leave no trace, so they are reported as definite.
+fn unavailable<V>(error: KvError<V>) -> Error {
+    let text = match error {
+        KvError::KeyNotFound => "thunk not found".to_string(),
+        KvError::PreconditionFailed { .. } => "unexpected precondition failure".to_string(),
+        KvError::Other(error) => error.text,
+    };
+    Error::new(ErrorCode::TemporarilyUnavailable, text)
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, ListAppendNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+    use serde_json::json;
+
+    type ListAppend = TestNode<ListAppendNode, (), Payload, InjectedPayload>;
+
+    fn start() -> ListAppend {
+        TestNode::start((), "n1", &["n1", "n2"])
+    }
+
+    fn txn(ops: Value) -> Payload {
+        Payload::Txn { txn: serde_json::from_value(ops).unwrap() }
+    }
+
+    /// Acknowledges every thunk write the transaction sent and returns them with the root `cas` that follows.
+    fn save(node: &mut ListAppend) -> (Vec<Value>, Value) {
+        let writes: Vec<Value> = node.sent().into_iter().map(|(_, body)| body).collect();
+        for _ in &writes {
+            node.inject(InjectedPayload::Saved { txn: 0, result: Ok(()) });
+        }
+        (writes, node.sent().pop().unwrap().1)
+    }
+
+    #[test]
+    fn a_missing_root_is_created_and_reads_see_earlier_appends() {
+        let mut n1 = start();
+        n1.deliver("c1", 1, txn(json!([["r", 1, null], ["append", 1, 5], ["r", 1, null], ["append", 1, 6]])));
+        n1.sent();
+        n1.inject(InjectedPayload::RootRead { txn: 0, result: Err(KvError::KeyNotFound) });
+
+        let (writes, cas) = save(&mut n1);
+        let list = writes.iter().find(|body| body["value"] == json!([5, 6])).unwrap();
+        let map = writes.iter().find(|body| body["value"] == json!([[1, list["key"]]])).unwrap();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(
+            (&cas["key"], &cas["from"], &cas["to"], &cas["create_if_not_exists"]),
+            (&json!(ROOT), &json!(""), &map["key"], &json!(true)),
+        );
+
+        n1.inject(InjectedPayload::RootCas { txn: 0, result: Ok(()) });
+        let reply = n1.sent().pop().unwrap().1;
+        assert_eq!(reply["txn"], json!([["r", 1, null], ["append", 1, 5], ["r", 1, [5]], ["append", 1, 6]]));
+    }
+
+    #[test]
+    fn a_root_moved_by_another_transaction_aborts_with_a_conflict() {
+        let mut n1 = start();
+        n1.deliver("c1", 1, txn(json!([["append", 1, 7], ["r", 1, null]])));
+        n1.sent();
+        n1.inject(InjectedPayload::RootRead { txn: 0, result: Ok("n2-0".to_string()) });
+        n1.inject(InjectedPayload::MapRead { txn: 0, id: "n2-0".to_string(), result: Ok(vec![(1, "n2-1".to_string())]) });
+        let reads: Vec<Value> = n1.sent().into_iter().map(|(_, body)| body["key"].clone()).collect();
+        assert_eq!(reads, [json!("n2-0"), json!("n2-1")]);
+        n1.inject(InjectedPayload::ListRead { txn: 0, id: "n2-1".to_string(), result: Ok(vec![json!(3)]) });
+
+        let (_, cas) = save(&mut n1);
+        assert_eq!((&cas["from"], &cas["create_if_not_exists"]), (&json!("n2-0"), &json!(false)));
+
+        let current = Some("n2-2".to_string());
+        n1.inject(InjectedPayload::RootCas { txn: 0, result: Err(KvError::PreconditionFailed { current }) });
+        let reply = n1.sent().pop().unwrap().1;
+        assert_eq!((&reply["type"], &reply["code"], &reply["in_reply_to"]), (&json!("error"), &json!(30), &json!(1)));
+        assert!(n1.node.pending.is_empty());
+    }
+
+    #[test]
+    fn thunk_ids_differ_between_runs_of_the_same_node() {
+        let ids: Vec<Value> = (0..2)
+            .map(|_| {
+                let mut n1 = start();
+                n1.deliver("c1", 1, txn(json!([["append", 1, 5]])));
+                n1.sent();
+                n1.inject(InjectedPayload::RootRead { txn: 0, result: Err(KvError::KeyNotFound) });
+                n1.sent()[0].1["key"].clone()
+            })
+            .collect();
+        assert!(ids[0].as_str().unwrap().starts_with("n1-"));
+        assert_ne!(ids[0], ids[1]);
+    }
+}
@@ -0,0 +1,310 @@
+use anyhow::bail;
+use maelstrom::{main_loop, Event, Init, Injector, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+const MODE_VAR: &str = "TXN_MODE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Every write is replicated on its own, intermediate writes included.
+    ReadUncommitted,
+    /// Only the final write of each key is replicated, one unit per transaction.
+    ReadCommitted,
+}
+
+impl Mode {
+    fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(MODE_VAR).as_deref() {
+            Err(_) | Ok("read-committed") => Ok(Mode::ReadCommitted),
+            Ok("read-uncommitted") => Ok(Mode::ReadUncommitted),
+            Ok(other) => bail!("unknown {MODE_VAR} {other:?}, expected read-uncommitted or read-committed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// A micro-op: `["r", key, null]` or `["w", key, value]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Op(OpKind, u64, Option<u64>);
+
+/// Total order of writes shared by every replica: a Lamport clock, then the writing node,
+/// then the write's position inside its transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+struct Version {
+    clock: u64,
+    node: String,
+    seq: usize,
+}
+
+/// Writes replicated and applied as one unit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Update {
+    version: Version,
+    writes: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+    Gossip { updates: Vec<Update> },
+    GossipOk { versions: Vec<Version> },
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Gossip,
+}
+
+struct TxnNode {
+    node_id: String,
+    mode: Mode,
+    clock: u64,
+    store: HashMap<u64, (Version, u64)>,
+    /// Updates some peer has not acknowledged yet.
+    updates: Vec<Update>,
+    /// Versions of `updates` each peer is known to hold.
+    known: HashMap<String, HashSet<Version>>,
+}
+
+impl Node<Mode, Payload, InjectedPayload> for TxnNode {
+    fn from_init(mode: Mode, message: Init, inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        inject.every(Duration::from_millis(300), InjectedPayload::Gossip);
+        Ok(TxnNode {
+            known: message.node_ids
+                .iter()
+                .filter(|n| **n != message.node_id)
+                .map(|n| (n.clone(), HashSet::new()))
+                .collect(),
+            node_id: message.node_id,
+            mode,
+            clock: 0,
+            store: HashMap::new(),
+            updates: Vec::new(),
+        })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Gossip) => {
+                for (node, known) in &self.known {
+                    let updates: Vec<Update> = self.updates
+                        .iter()
+                        .filter(|update| !known.contains(&update.version))
+                        .cloned()
+                        .collect();
+                    if !updates.is_empty() {
+                        output.send(node, Payload::Gossip { updates })?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TxnNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let src = msg.src.clone();
+        let mut reply = msg.into_reply(Some(output.next_msg_id()));
+        match reply.body.payload {
+            Payload::Txn { txn } => {
+                let txn = self.execute(txn);
+                reply.body.payload = Payload::TxnOk { txn };
+                reply.send(output)?;
+            }
+            Payload::Gossip { updates } => {
+                let versions: Vec<Version> = updates.iter().map(|update| update.version.clone()).collect();
+                for update in updates {
+                    self.apply(update);
+                }
+                if let Some(known) = self.known.get_mut(&src) {
+                    known.extend(versions.iter().cloned());
+                }
+                reply.body.payload = Payload::GossipOk { versions };
+                reply.send(output)?;
+            }
+            Payload::GossipOk { versions } => {
+                if let Some(known) = self.known.get_mut(&src) {
+                    known.extend(versions);
+                }
+            }
+            Payload::TxnOk { .. } => {}
+        }
+        self.compact();
+        Ok(())
+    }
+
+    /// Runs `txn` against the local store, filling in its reads, and records its writes for replication.
+    fn execute(&mut self, txn: Vec<Op>) -> Vec<Op> {
+        self.clock += 1;
+        let mut local: HashMap<u64, u64> = HashMap::new();
+        let mut writes: Vec<(u64, u64)> = Vec::new();
+        let txn = txn
+            .into_iter()
+            .map(|Op(kind, key, value)| match kind {
+                OpKind::Read => {
+                    let value = local
+                        .get(&key)
+                        .copied()
+                        .or_else(|| self.store.get(&key).map(|(_, value)| *value));
+                    Op(kind, key, value)
+                }
+                OpKind::Write => {
+                    if let Some(value) = value {
+                        local.insert(key, value);
+                        writes.push((key, value));
+                    }
+                    Op(kind, key, value)
+                }
+            })
+            .collect();
+
+        let updates: Vec<Update> = match self.mode {
+            Mode::ReadUncommitted => writes
+                .into_iter()
+                .enumerate()
+                .map(|(seq, write)| Update { version: self.version(seq), writes: vec![write] })
+                .collect(),
+            Mode::ReadCommitted => {
+                let last: BTreeMap<u64, u64> = writes.into_iter().collect();
+                if last.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![Update { version: self.version(0), writes: last.into_iter().collect() }]
+                }
+            }
+        };
+        for update in updates {
+            self.apply(update);
+        }
+        txn
+    }
+
+    fn version(&self, seq: usize) -> Version {
+        Version { clock: self.clock, node: self.node_id.clone(), seq }
+    }
+
+    /// Installs every write of `update` that is newer than what the store holds. An update with
+    /// no such write is a duplicate or already overwritten everywhere it applies, so it is dropped.
+    fn apply(&mut self, update: Update) {
+        self.clock = self.clock.max(update.version.clock);
+        let mut newer = false;
+        for (key, value) in &update.writes {
+            match self.store.get(key) {
+                Some((version, _)) if *version >= update.version => {}
+                _ => {
+                    self.store.insert(*key, (update.version.clone(), *value));
+                    newer = true;
+                }
+            }
+        }
+        if newer {
+            self.updates.push(update);
+        }
+    }
+
+    /// Forgets the updates every peer has acknowledged.
+    fn compact(&mut self) {
+        let known = &self.known;
+        self.updates.retain(|update| !known.values().all(|versions| versions.contains(&update.version)));
+        let pending: HashSet<&Version> = self.updates.iter().map(|update| &update.version).collect();
+        for versions in self.known.values_mut() {
+            versions.retain(|version| pending.contains(version));
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, TxnNode, _, _>(Mode::from_env()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+    use serde_json::json;
+
+    type Txn = TestNode<TxnNode, Mode, Payload, InjectedPayload>;
+
+    fn update(clock: u64, node: &str, writes: &[(u64, u64)]) -> Update {
+        Update {
+            version: Version { clock, node: node.to_string(), seq: 0 },
+            writes: writes.to_vec(),
+        }
+    }
+
+    fn value(txn: &Txn, key: u64) -> Option<u64> {
+        txn.node.store.get(&key).map(|(_, value)| *value)
+    }
+
+    #[test]
+    fn reads_see_earlier_writes_of_the_same_transaction() {
+        let mut n1 = Txn::start(Mode::ReadCommitted, "n1", &["n1"]);
+        n1.node.apply(update(1, "n2", &[(1, 3)]));
+
+        let txn = n1.node.execute(vec![
+            Op(OpKind::Read, 1, None),
+            Op(OpKind::Write, 1, Some(5)),
+            Op(OpKind::Read, 1, None),
+            Op(OpKind::Read, 2, None),
+        ]);
+        let reads: Vec<Option<u64>> = txn.iter().map(|Op(_, _, value)| *value).collect();
+        assert_eq!(reads, [Some(3), Some(5), Some(5), None]);
+        assert_eq!(value(&n1, 1), Some(5));
+    }
+
+    #[test]
+    fn apply_keeps_the_highest_version_of_each_key() {
+        let mut n1 = Txn::start(Mode::ReadCommitted, "n1", &["n1"]);
+        n1.node.apply(update(2, "n2", &[(1, 20), (2, 20)]));
+        n1.node.apply(update(1, "n3", &[(1, 10)]));
+        n1.node.apply(update(2, "n3", &[(2, 23)]));
+        n1.node.apply(update(2, "n1", &[(2, 21)]));
+
+        assert_eq!(value(&n1, 1), Some(20));
+        assert_eq!(value(&n1, 2), Some(23));
+        assert_eq!(n1.node.clock, 2);
+    }
+
+    #[test]
+    fn updates_are_gossiped_until_every_peer_acknowledges_them() {
+        let mut n1 = Txn::start(Mode::ReadCommitted, "n1", &["n1", "n2", "n3"]);
+        n1.deliver("c1", 1, Payload::Txn { txn: vec![Op(OpKind::Write, 1, Some(5))] });
+        n1.sent();
+
+        n1.inject(InjectedPayload::Gossip);
+        assert_eq!(n1.sent().len(), 2);
+
+        let version = update(1, "n1", &[]).version;
+        n1.deliver("n2", 1, Payload::GossipOk { versions: vec![version.clone()] });
+        n1.inject(InjectedPayload::Gossip);
+        let sent = n1.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "n3");
+
+        n1.deliver("n3", 1, Payload::GossipOk { versions: vec![version] });
+        assert!(n1.node.updates.is_empty());
+        assert!(n1.node.known.values().all(HashSet::is_empty));
+
+        // A late copy of the forgotten update is recognised by its version and not gossiped again.
+        n1.deliver("n2", 2, Payload::Gossip { updates: vec![update(1, "n1", &[(1, 5)])] });
+        assert_eq!(n1.sent()[0].1["type"], json!("gossip_ok"));
+        assert!(n1.node.updates.is_empty());
+    }
+}
@@ -1,42 +1,65 @@
 use anyhow::bail;
+use maelstrom::{
+    main_loop, Error, ErrorCode, Event, FlakeGenerator, Init, Injector, KvClient, KvError, KvService, Message, Node,
+    Output, PersistentSequence, Timestamp, TsoClient,
+};
 use serde::{Deserialize, Serialize};
-use std::io::{self, StdoutLock, Write, Lines, StdinLock};
+use std::collections::VecDeque;
+use std::path::PathBuf;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message<T> {
-    src: String,
-    #[serde(rename = "dest")]
-    dst: String,
-    body: Body<T>,
-}
+/// `uuid` (default), `flake`, `tso`, `lease` or `counter`.
+const MODE_VAR: &str = "UNIQUE_ID_MODE";
+/// Holds each node's `counter` checkpoint, the end of the block of sequence values it has
+/// reserved, so that ids keep clear of earlier runs. Defaults to the system temporary directory.
+const STATE_DIR_VAR: &str = "UNIQUE_ID_STATE_DIR";
+/// How many sequence values one `counter` checkpoint reserves.
+const SEQUENCE_BLOCK: u64 = 1000;
+/// lin-kv key holding the first id not leased yet.
+const LEASE_KEY: &str = "next-id";
+/// How many ids one lease covers.
+const LEASE_BLOCK: u64 = 100;
+/// Bits of a `tso` id left for the position of the request in its `lin-tso` batch.
+const TSO_SEQ_BITS: u32 = 20;
 
-impl<T> Message<T> where T: Serialize {
-    fn into_reply(self, id: Option<usize>) -> Self {
-        Message {
-            src: self.dst,
-            dst: self.src,
-            body: Body {
-                msg_id: id,
-                in_reply_to: self.body.msg_id,
-                payload: self.body.payload, 
-            }
-        }
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    /// Random v4 UUID strings.
+    Uuid,
+    /// 64-bit k-ordered numbers (see [`FlakeGenerator`]).
+    Flake,
+    /// Numbers built from `lin-tso` timestamps. The only mode whose ids increase across the whole
+    /// cluster, in real-time order.
+    Tso,
+    /// Numbers handed out from blocks leased with `cas` on a lin-kv counter. They increase on each
+    /// node but not across the cluster: a node still serving an older block hands out ids below
+    /// those another node has already served from a newer one.
+    Lease,
+    /// `<node_id>-<n>` strings, with `n` from a sequence checkpointed under `dir` so it survives
+    /// restarts.
+    Counter { dir: PathBuf },
+}
 
-    fn send(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut *output, &self)?;
-        output.write_all(b"\n")?;
-        Ok(())
+impl Mode {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(match std::env::var(MODE_VAR).as_deref() {
+            Err(_) | Ok("uuid") => Mode::Uuid,
+            Ok("flake") => Mode::Flake,
+            Ok("tso") => Mode::Tso,
+            Ok("lease") => Mode::Lease,
+            Ok("counter") => Mode::Counter {
+                dir: std::env::var_os(STATE_DIR_VAR).map_or_else(std::env::temp_dir, PathBuf::from),
+            },
+            Ok(other) => bail!("unknown {MODE_VAR} {other:?}, expected uuid, flake, tso, lease or counter"),
+        })
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Body<T> {
-    msg_id: Option<usize>,
-    in_reply_to: Option<usize>,
-    #[serde(flatten)]
-    payload: T,
+#[serde(untagged)]
+enum Id {
+    Text(String),
+    Number(u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,77 +67,252 @@ struct Body<T> {
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Generate,
-    GenerateOk { id: String, }
+    GenerateOk { id: Id, }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum InitPayload {
-    Init(Init),
-    InitOk,
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Ts(Result<u64, Error>),
+    Leased { from: u64, result: Result<(), KvError<u64>> },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Init {
-    node_id: String,
-    node_ids: Vec<String>,
+/// Ids leased from lin-kv and the requests waiting for the next lease.
+struct Lease {
+    kv: KvClient,
+    /// Last value of the lin-kv counter this node knows of.
+    counter: u64,
+    next: u64,
+    end: u64,
+    waiting: VecDeque<Message<Payload>>,
+    requesting: bool,
 }
 
-struct Node {
-    id: usize,
+enum Generator {
+    Uuid,
+    Flake(FlakeGenerator),
+    Tso(TsoClient<Message<Payload>, InjectedPayload>),
+    Lease(Lease),
+    Counter(PersistentSequence),
+}
+
+struct UniqueIdNode {
     node_id: String,
+    generator: Generator,
+    /// Ids handed out while the id service was unreachable.
+    fallbacks: u64,
 }
 
-impl Node {
-    fn from_init(msg: Init) -> Self {
-        Node { id: 0, node_id: msg.node_id }
+impl Node<Mode, Payload, InjectedPayload> for UniqueIdNode {
+    fn from_init(mode: Mode, init: Init, _inject: Injector<InjectedPayload>) -> anyhow::Result<Self> {
+        let generator = match mode {
+            Mode::Uuid => Generator::Uuid,
+            Mode::Flake => {
+                let Some(index) = init.node_ids.iter().position(|n| *n == init.node_id) else {
+                    bail!("{} is not in node_ids", init.node_id);
+                };
+                Generator::Flake(FlakeGenerator::new(index)?)
+            }
+            Mode::Tso => Generator::Tso(TsoClient::new(InjectedPayload::Ts)),
+            Mode::Lease => Generator::Lease(Lease {
+                kv: KvClient::new(KvService::Lin),
+                counter: 0,
+                next: 0,
+                end: 0,
+                waiting: VecDeque::new(),
+                requesting: false,
+            }),
+            Mode::Counter { dir } => {
+                let path = dir.join(format!("unique-id-{}.seq", init.node_id));
+                Generator::Counter(PersistentSequence::open(path, SEQUENCE_BLOCK)?)
+            }
+        };
+        Ok(UniqueIdNode { node_id: init.node_id, generator, fallbacks: 0 })
+    }
+
+    fn step(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::Eof => {}
+            Event::Message(msg) => {
+                self.process(msg, output)?;
+            }
+            Event::Injected(InjectedPayload::Ts(result)) => {
+                let Generator::Tso(tso) = &mut self.generator else {
+                    return Ok(());
+                };
+                for (request, result) in tso.complete(output, result)? {
+                    let id = match result {
+                        Ok(Timestamp { ts, seq }) if seq < 1 << TSO_SEQ_BITS => Id::Number(ts << TSO_SEQ_BITS | seq as u64),
+                        _ => self.fallback(),
+                    };
+                    answer(request, id, output)?;
+                }
+            }
+            Event::Injected(InjectedPayload::Leased { from, result }) => {
+                let Generator::Lease(lease) = &mut self.generator else {
+                    return Ok(());
+                };
+                lease.requesting = false;
+                match result {
+                    Ok(()) => {
+                        lease.counter = from + LEASE_BLOCK;
+                        lease.next = from;
+                        lease.end = from + LEASE_BLOCK;
+                    }
+                    // Someone else leased first: try again from where they left the counter.
+                    Err(KvError::PreconditionFailed { current: Some(current) }) => lease.counter = current,
+                    Err(error) => {
+                        eprintln!("{} could not lease ids: {error:?}", self.node_id);
+                        let waiting = std::mem::take(&mut lease.waiting);
+                        for request in waiting {
+                            let id = self.fallback();
+                            answer(request, id, output)?;
+                        }
+                        return Ok(());
+                    }
+                }
+                self.serve_leased(output)?;
+            }
+        }
+        Ok(())
     }
+}
 
-    fn process(&mut self, msg: Message<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let mut reply = msg.into_reply(Some(self.id));
-        match reply.body.payload {
+impl UniqueIdNode {
+    fn process(&mut self, msg: Message<Payload>, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        match msg.body.payload {
             Payload::Generate => {
-                reply.body.payload =  Payload::GenerateOk { id: Uuid::new_v4().to_string() };
-                reply.send(output)?;
+                let id = match &mut self.generator {
+                    Generator::Uuid => Id::Text(Uuid::new_v4().to_string()),
+                    Generator::Flake(flake) => Id::Number(flake.next_id()),
+                    Generator::Counter(sequence) => Id::Text(format!("{}-{}", self.node_id, sequence.next_value()?)),
+                    Generator::Tso(tso) => return tso.ts(output, msg),
+                    Generator::Lease(lease) => {
+                        lease.waiting.push_back(msg);
+                        return self.serve_leased(output);
+                    }
+                };
+                answer(msg, id, output)?;
             }
             Payload::GenerateOk { .. } => {
-                bail!("Should not receive generate_ok message at Node {}", self.node_id)
+                return Err(Error::new(
+                    ErrorCode::NotSupported,
+                    format!("Should not receive generate_ok message at Node {}", self.node_id),
+                ).into());
             }
         };
         Ok(())
     }
+
+    /// Answers waiting requests from the current lease, asking for a new one once it runs out.
+    fn serve_leased(&mut self, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+        let Generator::Lease(lease) = &mut self.generator else {
+            return Ok(());
+        };
+        while lease.next < lease.end {
+            let Some(request) = lease.waiting.pop_front() else {
+                break;
+            };
+            answer(request, Id::Number(lease.next), output)?;
+            lease.next += 1;
+        }
+        if !lease.waiting.is_empty() && !lease.requesting {
+            lease.requesting = true;
+            let from = lease.counter;
+            let create = from == 0;
+            lease.kv.cas(output, LEASE_KEY, from, from + LEASE_BLOCK, create, move |result| {
+                InjectedPayload::Leased { from, result }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// A node-prefixed id, flagged as not coming from the id service.
+    fn fallback(&mut self) -> Id {
+        self.fallbacks += 1;
+        Id::Text(format!("{}-fallback-{}", self.node_id, self.fallbacks))
+    }
+}
+
+fn answer(request: Message<Payload>, id: Id, output: &mut Output<InjectedPayload>) -> anyhow::Result<()> {
+    let mut reply = request.into_reply(Some(output.next_msg_id()));
+    reply.body.payload = Payload::GenerateOk { id };
+    reply.send(output)
 }
 
 fn main() -> anyhow::Result<()> {
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-
-    let mut stdin = stdin.lines();
-
-    let init_msg = wait_for_initialization(&mut stdin, &mut stdout.lock())
-        .expect("Expected init message");
-    let mut node = Node::from_init(init_msg);
-        
-    for line in stdin {
-        let input: Message<Payload> = serde_json::from_str(&line?)?;
-        node.process(input, &mut stdout.lock())
-            .unwrap_or_else(|_| panic!("Error processing message at Node {}", node.node_id));
-        node.id += 1;
-    }
-    Ok(())
+    main_loop::<_, UniqueIdNode, _, _>(Mode::from_env()?)
 }
 
-fn wait_for_initialization(input: &mut Lines<StdinLock>, output: &mut StdoutLock) -> anyhow::Result<Init> {
-    let msg: Message<InitPayload> = serde_json::from_str(
-        &input
-        .next()
-        .expect("no message received")?)?;
-    let InitPayload::Init(init_msg) = msg.body.payload.clone() else {
-        bail!("Expected init message")
-    };
-    let mut reply = msg.into_reply(Some(0));
-    reply.body.payload = InitPayload::InitOk;
-    reply.send(output)?;
-    Ok(init_msg)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::testing::TestNode;
+    use serde_json::{json, Value};
+
+    type UniqueId = TestNode<UniqueIdNode, Mode, Payload, InjectedPayload>;
+
+    fn start(mode: Mode) -> UniqueId {
+        TestNode::start(mode, "n1", &["n1", "n2"])
+    }
+
+    /// Asks for `count` ids and returns what came back: ids, or the lease `cas` requests.
+    fn generate(node: &mut UniqueId, count: usize) -> Vec<(String, Value)> {
+        for msg_id in 0..count {
+            node.deliver("c1", msg_id, Payload::Generate);
+        }
+        node.sent()
+    }
+
+    fn ids(replies: Vec<(String, Value)>) -> Vec<Value> {
+        replies.into_iter().map(|(_, body)| body["id"].clone()).collect()
+    }
+
+    fn lease_range(cas: &Value) -> (u64, u64, bool) {
+        (cas["from"].as_u64().unwrap(), cas["to"].as_u64().unwrap(), cas["create_if_not_exists"].as_bool().unwrap())
+    }
+
+    #[test]
+    fn a_new_block_is_leased_once_the_current_one_runs_out() {
+        let mut n1 = start(Mode::Lease);
+        let cas = generate(&mut n1, 1);
+        assert_eq!(lease_range(&cas[0].1), (0, LEASE_BLOCK, true));
+        n1.inject(InjectedPayload::Leased { from: 0, result: Ok(()) });
+        assert_eq!(ids(n1.sent()), [json!(0)]);
+
+        let served = ids(generate(&mut n1, LEASE_BLOCK as usize - 1));
+        assert_eq!(served.first(), Some(&json!(1)));
+        assert_eq!(served.last(), Some(&json!(LEASE_BLOCK - 1)));
+
+        let cas = generate(&mut n1, 1);
+        assert_eq!(lease_range(&cas[0].1), (LEASE_BLOCK, 2 * LEASE_BLOCK, false));
+    }
+
+    #[test]
+    fn a_lost_lease_race_retries_from_the_current_counter() {
+        let mut n1 = start(Mode::Lease);
+        generate(&mut n1, 2);
+        let current = Some(3 * LEASE_BLOCK);
+        n1.inject(InjectedPayload::Leased { from: 0, result: Err(KvError::PreconditionFailed { current }) });
+        let cas = n1.sent();
+        assert_eq!(lease_range(&cas[0].1), (3 * LEASE_BLOCK, 4 * LEASE_BLOCK, false));
+
+        n1.inject(InjectedPayload::Leased { from: 3 * LEASE_BLOCK, result: Ok(()) });
+        assert_eq!(ids(n1.sent()), [json!(3 * LEASE_BLOCK), json!(3 * LEASE_BLOCK + 1)]);
+    }
+
+    #[test]
+    fn an_unreachable_id_service_gets_flagged_fallback_ids() {
+        let timeout = || Error::new(ErrorCode::Timeout, "no reply");
+
+        let mut leased = start(Mode::Lease);
+        generate(&mut leased, 2);
+        leased.inject(InjectedPayload::Leased { from: 0, result: Err(KvError::Other(timeout())) });
+        assert_eq!(ids(leased.sent()), [json!("n1-fallback-1"), json!("n1-fallback-2")]);
+
+        let mut tso = start(Mode::Tso);
+        let request = generate(&mut tso, 1);
+        assert_eq!((request[0].0.as_str(), &request[0].1["type"]), ("lin-tso", &json!("ts")));
+        tso.inject(InjectedPayload::Ts(Err(timeout())));
+        assert_eq!(ids(tso.sent()), [json!("n1-fallback-1")]);
+    }
 }
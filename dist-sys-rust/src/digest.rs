@@ -0,0 +1,119 @@
+use crate::fnv::Fnv1a;
+use crate::interval_set::IntervalSet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+/// Digests hash a set in buckets of this many consecutive values.
+const DIGEST_BUCKET_WIDTH: usize = 64;
+
+/// Hash of the values of each non-empty bucket of an [`IntervalSet`], for finding out which
+/// parts of two sets differ without sending either of them.
+///
+/// Buckets are hashed with [`Fnv1a`], so two nodes holding the same values in a bucket send the same
+/// hash. Hashes are kept below 2^53 so they survive JSON parsers that read numbers as doubles.
+/// Serialized as a list of `[bucket, hash]` pairs, as map keys would be strings in JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Digest {
+    buckets: BTreeMap<usize, u64>,
+}
+
+impl Digest {
+    pub fn of(values: &IntervalSet) -> Self {
+        let mut hashers: BTreeMap<usize, Fnv1a> = BTreeMap::new();
+        for (start, end) in values.runs() {
+            for bucket in start / DIGEST_BUCKET_WIDTH..=end / DIGEST_BUCKET_WIDTH {
+                let first = bucket * DIGEST_BUCKET_WIDTH;
+                let run = (start.max(first), end.min(first + DIGEST_BUCKET_WIDTH - 1));
+                run.hash(hashers.entry(bucket).or_default());
+            }
+        }
+        let buckets = hashers
+            .into_iter()
+            .map(|(bucket, hasher)| (bucket, hasher.finish() & ((1 << 53) - 1)))
+            .collect();
+        Digest { buckets }
+    }
+
+    /// Buckets whose hashes differ, including those only one side has values in.
+    pub fn differing(&self, other: &Digest) -> IntervalSet {
+        self.buckets
+            .keys()
+            .chain(other.buckets.keys())
+            .copied()
+            .filter(|bucket| self.buckets.get(bucket) != other.buckets.get(bucket))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// Every value that falls in one of `buckets`.
+pub fn bucket_values(buckets: &IntervalSet) -> IntervalSet {
+    let mut values = IntervalSet::new();
+    for (start, end) in buckets.runs() {
+        values.insert_run(start * DIGEST_BUCKET_WIDTH, end * DIGEST_BUCKET_WIDTH + DIGEST_BUCKET_WIDTH - 1);
+    }
+    values
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&self.buckets)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let buckets: Vec<(usize, u64)> = Vec::deserialize(deserializer)?;
+        Ok(Digest { buckets: buckets.into_iter().collect() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn set(runs: &[(usize, usize)]) -> IntervalSet {
+        let mut set = IntervalSet::new();
+        for (start, end) in runs {
+            set.insert_run(*start, *end);
+        }
+        set
+    }
+
+    #[test]
+    fn a_round_finds_exactly_the_differing_buckets() {
+        // Bucket 0 lacks 5 on one side, bucket 4 only exists on the other; 1 to 3 match.
+        let mine = set(&[(0, 4), (6, 199)]);
+        let theirs = set(&[(0, 199), (300, 300)]);
+        let differing = Digest::of(&theirs).differing(&Digest::of(&mine));
+        assert_eq!(differing, set(&[(0, 0), (4, 4)]));
+
+        // The replier sends its values in those buckets, and gets back what only the asker has.
+        let covered = bucket_values(&differing);
+        assert_eq!(covered, set(&[(0, 63), (256, 319)]));
+        let replied = mine.intersection(&covered);
+        let mut asker = theirs.clone();
+        let missing = asker.intersection(&covered).difference(&replied);
+        asker.union(&replied);
+        let mut replier = mine.clone();
+        replier.union(&missing);
+        assert_eq!(asker, replier);
+        assert!(Digest::of(&asker).differing(&Digest::of(&replier)).is_empty());
+    }
+
+    #[test]
+    fn serializes_as_bucket_hash_pairs() {
+        let digest = Digest::of(&set(&[(0, 0), (64, 64)]));
+        let value = serde_json::to_value(&digest).unwrap();
+        assert_eq!(value.as_array().unwrap().len(), 2);
+        assert_eq!(value[1][0], json!(1));
+        assert!(value[0][1].as_u64().unwrap() < 1 << 53);
+        assert_eq!(serde_json::from_value::<Digest>(value).unwrap(), digest);
+        assert!(Digest::of(&IntervalSet::new()).is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::Context;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The number written to `path`, as by [`replace_file`], or `None` when there is no file yet.
+/// `what` names the number in errors.
+pub fn read_number<T>(path: &Path, what: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let number = contents.trim().parse().with_context(|| format!("invalid {what} in {}", path.display()))?;
+            Ok(Some(number))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Replaces the contents of `path` through a synced temporary file and a rename, then syncs the
+/// directory so the rename itself survives a crash. Afterwards the file holds either the old
+/// contents or the new ones, never a mix.
+pub fn replace_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = fs::File::create(&temporary).with_context(|| format!("creating {}", temporary.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path).with_context(|| format!("replacing {}", path.display()))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("syncing {}", directory.display()))
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Error codes defined by the Maelstrom protocol; codes 1000 and above are free for workloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "usize", into = "usize")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Custom(usize),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> usize {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+
+    /// A definite error guarantees the operation did not and never will take place.
+    /// Timeouts, crashes and custom codes may have taken effect, so they are indefinite.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Custom(_))
+    }
+}
+
+impl From<usize> for ErrorCode {
+    fn from(code: usize) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for usize {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+/// Body of an `error` message.
+///
+/// Returning it (through `anyhow`) from [`Node::step`](crate::Node::step) while handling a
+/// request makes the event loop answer that request with this error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Error {
+    pub code: ErrorCode,
+    #[serde(default)]
+    pub text: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Error { code, text: text.into() }
+    }
+
+    pub fn is_definite(&self) -> bool {
+        self.code.is_definite()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {} ({:?}): {}", self.code.code(), self.code, self.text)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorPayload {
+    Error(Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn codes_round_trip() {
+        for code in [0, 1, 10, 11, 12, 13, 14, 20, 21, 22, 30, 1000] {
+            assert_eq!(ErrorCode::from(code).code(), code);
+        }
+        assert_eq!(ErrorCode::from(22), ErrorCode::PreconditionFailed);
+        assert_eq!(ErrorCode::from(5), ErrorCode::Custom(5));
+    }
+
+    #[test]
+    fn definite_classification_follows_protocol() {
+        assert!(!ErrorCode::Timeout.is_definite());
+        assert!(!ErrorCode::Crash.is_definite());
+        assert!(!ErrorCode::Custom(1001).is_definite());
+        assert!(ErrorCode::Abort.is_definite());
+        assert!(ErrorCode::KeyDoesNotExist.is_definite());
+        assert!(ErrorCode::TxnConflict.is_definite());
+    }
+
+    #[test]
+    fn serializes_as_error_body() {
+        let payload = ErrorPayload::Error(Error::new(ErrorCode::KeyDoesNotExist, "not found"));
+        assert_eq!(
+            serde_json::to_value(payload).unwrap(),
+            json!({"type": "error", "code": 20, "text": "not found"})
+        );
+        let error: Error = serde_json::from_value(json!({"type": "error", "code": 11})).unwrap();
+        assert_eq!(error, Error::new(ErrorCode::TemporarilyUnavailable, ""));
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::bail;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 2020-01-01T00:00:00Z in Unix milliseconds; 41 bits of milliseconds from it last until 2089.
+const EPOCH_MS: u64 = 1_577_836_800_000;
+const NODE_BITS: u32 = 10;
+const SEQ_BITS: u32 = 12;
+const MAX_SEQ: u64 = (1 << SEQ_BITS) - 1;
+
+/// Generator of 63-bit k-ordered ids: milliseconds since 2020, node index, per-node sequence.
+///
+/// Ids never repeat and only grow on one node, even if the wall clock goes back: the generator
+/// keeps counting from the last millisecond it used, and moves on to the next one when the
+/// sequence runs out, so it never has to wait.
+#[derive(Debug, Clone)]
+pub struct FlakeGenerator {
+    node: u64,
+    /// Millisecond of the last id, which may run ahead of the clock.
+    last: u64,
+    seq: u64,
+}
+
+impl FlakeGenerator {
+    /// `node_index` is the node's position in `Init::node_ids`, below 1024.
+    pub fn new(node_index: usize) -> anyhow::Result<Self> {
+        if node_index >= 1 << NODE_BITS {
+            bail!("flake ids support at most {} nodes, got index {node_index}", 1 << NODE_BITS);
+        }
+        Ok(FlakeGenerator { node: node_index as u64, last: 0, seq: 0 })
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.next_at(now.saturating_sub(EPOCH_MS))
+    }
+
+    /// Next id for a clock reading of `now` milliseconds since the epoch.
+    fn next_at(&mut self, now: u64) -> u64 {
+        if now > self.last {
+            self.last = now;
+            self.seq = 0;
+        } else if self.seq < MAX_SEQ {
+            self.seq += 1;
+        } else {
+            self.last += 1;
+            self.seq = 0;
+        }
+        (self.last << (NODE_BITS + SEQ_BITS)) | (self.node << SEQ_BITS) | self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(id: u64) -> (u64, u64, u64) {
+        (id >> (NODE_BITS + SEQ_BITS), (id >> SEQ_BITS) & ((1 << NODE_BITS) - 1), id & MAX_SEQ)
+    }
+
+    #[test]
+    fn packs_time_node_and_sequence() {
+        let mut flake = FlakeGenerator::new(3).unwrap();
+        assert_eq!(parts(flake.next_at(100)), (100, 3, 0));
+        assert_eq!(parts(flake.next_at(100)), (100, 3, 1));
+        assert_eq!(parts(flake.next_at(101)), (101, 3, 0));
+        assert!(FlakeGenerator::new(1024).is_err());
+        assert!(FlakeGenerator::new(2).unwrap().next_id() < 1 << 63);
+    }
+
+    #[test]
+    fn keeps_growing_when_the_clock_goes_back_or_the_sequence_runs_out() {
+        let mut flake = FlakeGenerator::new(0).unwrap();
+        let mut last = flake.next_at(500);
+        for _ in 0..MAX_SEQ + 10 {
+            let id = flake.next_at(400);
+            assert!(id > last);
+            last = id;
+        }
+        assert_eq!(parts(last), (501, 0, 9));
+        assert_eq!(parts(flake.next_at(501)), (501, 0, 10));
+        assert_eq!(parts(flake.next_at(600)), (600, 0, 0));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::random::random_below;
+use std::collections::HashSet;
+
+const ACTIVE_SIZE: usize = 5;
+const PASSIVE_SIZE: usize = 30;
+/// Active and passive random walk lengths of a `ForwardJoin`.
+const ACTIVE_WALK: usize = 4;
+const PASSIVE_WALK: usize = 2;
+/// Shuffle the passive view every this many ticks.
+const SHUFFLE_EVERY: usize = 5;
+/// How many active and passive peers a shuffle carries.
+const SHUFFLE_ACTIVE: usize = 3;
+const SHUFFLE_PASSIVE: usize = 4;
+
+/// A message for the caller to send on the view's behalf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Join { to: String },
+    ForwardJoin { to: String, node: String, ttl: usize },
+    /// Asks `to` to take us into its active view; the answer goes to
+    /// [`neighbor_reply`](HyParView::neighbor_reply).
+    Connect { to: String, high_priority: bool },
+    Disconnect { to: String },
+    Shuffle { to: String, origin: String, nodes: Vec<String>, ttl: usize },
+    ShuffleReply { to: String, nodes: Vec<String> },
+    /// Checks that a neighbor is alive; one that does not answer goes to
+    /// [`demote`](HyParView::demote).
+    Ping { to: String },
+}
+
+/// HyParView partial-view membership: a small active view of neighbors, and a larger passive
+/// view of known nodes to replace the ones that fail. Decides what goes where; sending the
+/// resulting [`Action`]s is up to the caller.
+#[derive(Debug)]
+pub struct HyParView {
+    me: String,
+    /// Node everybody joins through.
+    contact: String,
+    active: Vec<String>,
+    passive: Vec<String>,
+    /// Passive nodes asked to become neighbors that have not answered yet.
+    pending: HashSet<String>,
+    ticks: usize,
+}
+
+impl HyParView {
+    pub fn new(me: &str, contact: &str) -> Self {
+        HyParView {
+            me: me.to_string(),
+            contact: contact.to_string(),
+            active: Vec::new(),
+            passive: Vec::new(),
+            pending: HashSet::new(),
+            ticks: 0,
+        }
+    }
+
+    pub fn active(&self) -> &[String] {
+        &self.active
+    }
+
+    pub fn is_active(&self, peer: &str) -> bool {
+        self.active.iter().any(|n| n == peer)
+    }
+
+    /// Joins through the contact while isolated, pings the active view, asks a passive node to
+    /// fill it when it is short and periodically starts a shuffle.
+    pub fn tick(&mut self) -> Vec<Action> {
+        self.ticks += 1;
+        let mut actions = Vec::new();
+        if self.active.is_empty() && self.passive.is_empty() && self.contact != self.me {
+            actions.push(Action::Join { to: self.contact.clone() });
+        }
+        actions.extend(self.active.iter().map(|peer| Action::Ping { to: peer.clone() }));
+        if self.active.len() < ACTIVE_SIZE && self.pending.is_empty() {
+            if let Some(candidate) = choose(self.passive.iter()) {
+                self.pending.insert(candidate.clone());
+                actions.push(Action::Connect { to: candidate, high_priority: self.active.is_empty() });
+            }
+        }
+        if self.ticks.is_multiple_of(SHUFFLE_EVERY) {
+            if let Some(target) = choose(self.active.iter()) {
+                let mut nodes = vec![self.me.clone()];
+                nodes.extend(sample(&self.active, SHUFFLE_ACTIVE));
+                nodes.extend(sample(&self.passive, SHUFFLE_PASSIVE));
+                actions.push(Action::Shuffle { to: target, origin: self.me.clone(), nodes, ttl: ACTIVE_WALK });
+            }
+        }
+        actions
+    }
+
+    /// `node` joined through us: it becomes a neighbor, and a walk starts at every other one.
+    pub fn join(&mut self, node: &str) -> Vec<Action> {
+        let mut actions = self.add_active(node);
+        actions.push(Action::Connect { to: node.to_string(), high_priority: true });
+        for peer in self.active.iter().filter(|peer| *peer != node) {
+            actions.push(Action::ForwardJoin { to: peer.clone(), node: node.to_string(), ttl: ACTIVE_WALK });
+        }
+        actions
+    }
+
+    /// A join walk for `node` arrived from `from`. It goes on while `ttl` lasts, leaving `node` in
+    /// the passive view at `PASSIVE_WALK`, and `node` becomes a neighbor where it ends.
+    pub fn forward_join(&mut self, from: &str, node: &str, ttl: usize) -> Vec<Action> {
+        let next = choose(self.active.iter().filter(|peer| *peer != from && *peer != node));
+        match next {
+            Some(next) if ttl > 0 && self.active.len() > 1 => {
+                if ttl == PASSIVE_WALK {
+                    self.add_passive(node);
+                }
+                vec![Action::ForwardJoin { to: next, node: node.to_string(), ttl: ttl - 1 }]
+            }
+            _ if node == self.me || self.is_active(node) => Vec::new(),
+            _ => {
+                let mut actions = self.add_active(node);
+                actions.push(Action::Connect { to: node.to_string(), high_priority: true });
+                actions
+            }
+        }
+    }
+
+    /// `from` asks to become a neighbor. A full active view only takes high-priority requests,
+    /// which come from nodes with no neighbors at all. Returns whether it was accepted.
+    pub fn neighbor_request(&mut self, from: &str, high_priority: bool) -> (bool, Vec<Action>) {
+        let accepted = high_priority || self.active.len() < ACTIVE_SIZE || self.is_active(from);
+        let actions = if accepted { self.add_active(from) } else { Vec::new() };
+        (accepted, actions)
+    }
+
+    /// Answer to a [`Action::Connect`] sent to `peer`.
+    pub fn neighbor_reply(&mut self, peer: &str, accepted: bool) -> Vec<Action> {
+        self.pending.remove(peer);
+        if accepted {
+            return self.add_active(peer);
+        }
+        self.demote(peer);
+        Vec::new()
+    }
+
+    /// Moves `peer` from the active view to the passive one: it disconnected, or stopped answering.
+    pub fn demote(&mut self, peer: &str) {
+        if self.is_active(peer) {
+            self.active.retain(|n| n != peer);
+            self.add_passive(peer);
+        }
+    }
+
+    /// A shuffle from `origin` arrived from `from`. It goes on while `ttl` lasts; where it ends,
+    /// `origin` is sent as many passive nodes as it offered, and takes the offered ones in.
+    pub fn shuffle(&mut self, from: &str, origin: &str, nodes: &[String], ttl: usize) -> Vec<Action> {
+        let next = choose(self.active.iter().filter(|peer| *peer != from && *peer != origin));
+        match next {
+            Some(next) if ttl > 1 => {
+                vec![Action::Shuffle { to: next, origin: origin.to_string(), nodes: nodes.to_vec(), ttl: ttl - 1 }]
+            }
+            _ => {
+                let reply = Action::ShuffleReply { to: origin.to_string(), nodes: sample(&self.passive, nodes.len()) };
+                for node in nodes {
+                    self.add_passive(node);
+                }
+                vec![reply]
+            }
+        }
+    }
+
+    /// The passive nodes sent back for a shuffle this node started.
+    pub fn shuffle_reply(&mut self, nodes: &[String]) {
+        for node in nodes {
+            self.add_passive(node);
+        }
+    }
+
+    /// Makes `peer` a neighbor, disconnecting a random one if the active view is full.
+    fn add_active(&mut self, peer: &str) -> Vec<Action> {
+        if peer == self.me || self.is_active(peer) {
+            return Vec::new();
+        }
+        let mut actions = Vec::new();
+        if self.active.len() >= ACTIVE_SIZE {
+            if let Some(victim) = choose(self.active.iter()) {
+                self.demote(&victim);
+                actions.push(Action::Disconnect { to: victim });
+            }
+        }
+        self.active.push(peer.to_string());
+        self.passive.retain(|n| n != peer);
+        actions
+    }
+
+    fn add_passive(&mut self, node: &str) {
+        if node == self.me || self.is_active(node) || self.passive.iter().any(|n| n == node) {
+            return;
+        }
+        if self.passive.len() >= PASSIVE_SIZE {
+            self.passive.swap_remove(random_below(self.passive.len()));
+        }
+        self.passive.push(node.to_string());
+    }
+}
+
+fn choose<'a>(items: impl Iterator<Item = &'a String>) -> Option<String> {
+    let items: Vec<&String> = items.collect();
+    if items.is_empty() {
+        return None;
+    }
+    Some(items[random_below(items.len())].clone())
+}
+
+/// Up to `count` distinct random entries of `items`.
+fn sample(items: &[String], count: usize) -> Vec<String> {
+    let mut items = items.to_vec();
+    let count = count.min(items.len());
+    for i in 0..count {
+        let j = i + random_below(items.len() - i);
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// A view of `n0` with `active` as neighbors and `passive` known.
+    fn with_views(active: &[&str], passive: &[&str]) -> HyParView {
+        let mut view = HyParView::new("n0", "n0");
+        for peer in active {
+            view.neighbor_request(peer, false);
+        }
+        for node in passive {
+            view.add_passive(node);
+        }
+        view
+    }
+
+    #[test]
+    fn a_full_active_view_evicts_a_neighbor_only_for_high_priority_requests() {
+        let mut view = with_views(&["n1", "n2", "n3", "n4", "n5"], &[]);
+        assert_eq!(view.neighbor_request("n6", false), (false, vec![]));
+        assert!(!view.is_active("n6"));
+        assert_eq!(view.neighbor_request("n1", false), (true, vec![]));
+
+        let (accepted, actions) = view.neighbor_request("n6", true);
+        assert!(accepted && view.is_active("n6"));
+        assert_eq!(view.active().len(), ACTIVE_SIZE);
+        let [Action::Disconnect { to: victim }] = actions.as_slice() else {
+            panic!("expected one disconnect, got {actions:?}");
+        };
+        assert!(!view.is_active(victim));
+        assert_eq!(view.passive, std::slice::from_ref(victim));
+    }
+
+    #[test]
+    fn forward_join_walks_until_its_ttl_runs_out() {
+        let mut view = with_views(&["n1", "n2"], &[]);
+        assert_eq!(view.forward_join("n1", "n9", ACTIVE_WALK), [Action::ForwardJoin {
+            to: "n2".to_string(),
+            node: "n9".to_string(),
+            ttl: ACTIVE_WALK - 1,
+        }]);
+        assert!(view.passive.is_empty());
+
+        // Halfway down the walk the joiner is remembered as a passive node.
+        view.forward_join("n1", "n9", PASSIVE_WALK);
+        assert_eq!(view.passive, ["n9"]);
+
+        // Where the walk ends it becomes a neighbor.
+        assert_eq!(view.forward_join("n1", "n9", 0), [Action::Connect { to: "n9".to_string(), high_priority: true }]);
+        assert!(view.is_active("n9") && view.passive.is_empty());
+        assert!(view.forward_join("n1", "n9", 0).is_empty());
+
+        // With a single neighbor there is nowhere to go on, whatever the ttl.
+        let mut lonely = with_views(&["n1"], &[]);
+        lonely.forward_join("n1", "n8", ACTIVE_WALK);
+        assert!(lonely.is_active("n8"));
+    }
+
+    #[test]
+    fn a_shuffle_trades_passive_nodes_with_the_origin() {
+        let mut view = with_views(&["n1", "n2"], &["p1", "p2", "p3"]);
+        let offered = names(&["n7", "q1"]);
+        assert_eq!(view.shuffle("n1", "n7", &offered, 3), [Action::Shuffle {
+            to: "n2".to_string(),
+            origin: "n7".to_string(),
+            nodes: offered.clone(),
+            ttl: 2,
+        }]);
+
+        let actions = view.shuffle("n1", "n7", &offered, 1);
+        let [Action::ShuffleReply { to, nodes }] = actions.as_slice() else {
+            panic!("expected a shuffle reply, got {actions:?}");
+        };
+        assert_eq!(to, "n7");
+        assert_eq!(nodes.len(), 2);
+        assert!(nodes.iter().all(|node| ["p1", "p2", "p3"].contains(&node.as_str())));
+        assert_eq!(view.passive, ["p1", "p2", "p3", "n7", "q1"]);
+
+        // Our own id and current neighbors never enter the passive view.
+        view.shuffle_reply(&names(&["n0", "n1", "q2"]));
+        assert_eq!(view.passive.last().unwrap(), "q2");
+        assert_eq!(view.passive.len(), 6);
+
+        // Every few ticks a shuffle offering ourselves and some of each view starts.
+        let shuffles: Vec<Action> = (0..SHUFFLE_EVERY)
+            .flat_map(|_| view.tick())
+            .filter(|action| matches!(action, Action::Shuffle { .. }))
+            .collect();
+        let [Action::Shuffle { origin, nodes, ttl, .. }] = shuffles.as_slice() else {
+            panic!("expected one shuffle, got {shuffles:?}");
+        };
+        assert_eq!((origin.as_str(), nodes[0].as_str(), *ttl), ("n0", "n0", ACTIVE_WALK));
+        assert_eq!(nodes.len(), 1 + 2 + SHUFFLE_PASSIVE);
+    }
+
+    #[test]
+    fn a_failed_neighbor_is_replaced_from_the_passive_view() {
+        let mut view = with_views(&["n1"], &["p1"]);
+        view.pending.insert("p1".to_string());
+        assert_eq!(view.tick(), [Action::Ping { to: "n1".to_string() }]);
+        view.neighbor_reply("p1", false);
+
+        // n1 stops answering pings: it goes passive, and a passive node is asked to step in,
+        // with high priority since we have no neighbors left.
+        view.demote("n1");
+        assert!(view.active().is_empty());
+        let actions = view.tick();
+        let [Action::Connect { to: candidate, high_priority: true }] = actions.as_slice() else {
+            panic!("expected a high-priority connect, got {actions:?}");
+        };
+        assert!(["n1", "p1"].contains(&candidate.as_str()));
+        assert!(view.tick().iter().all(|action| !matches!(action, Action::Connect { .. })));
+
+        let candidate = candidate.clone();
+        view.neighbor_reply(&candidate, true);
+        assert_eq!(view.active(), std::slice::from_ref(&candidate));
+        assert!(!view.passive.contains(&candidate));
+        assert!(view.pending.is_empty());
+    }
+
+    #[test]
+    fn an_isolated_node_joins_through_the_contact() {
+        let mut view = HyParView::new("n3", "n0");
+        assert_eq!(view.tick(), [Action::Join { to: "n0".to_string() }]);
+        assert!(HyParView::new("n0", "n0").tick().is_empty());
+
+        let mut contact = with_views(&["n1", "n2"], &[]);
+        let actions = contact.join("n3");
+        assert!(contact.is_active("n3"));
+        assert_eq!(actions[0], Action::Connect { to: "n3".to_string(), high_priority: true });
+        let walks: Vec<&str> = actions[1..]
+            .iter()
+            .map(|action| match action {
+                Action::ForwardJoin { to, node, ttl } if node == "n3" && *ttl == ACTIVE_WALK => to.as_str(),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        assert_eq!(walks, ["n1", "n2"]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::interval_set::IntervalSet;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Floor of the variance term of the timeout, so a steady round trip still leaves some slack.
+const GRANULARITY: Duration = Duration::from_millis(10);
+
+/// Values sent to each peer and not acknowledged yet, by when they were last sent, plus a
+/// round-trip time learned from the acknowledgements.
+///
+/// A value is only worth sending again once it has gone unacknowledged for longer than a round
+/// trip; until then its acknowledgement may simply still be on the way. The timeout follows TCP
+/// (RFC 6298): the smoothed round trip plus four times its mean deviation.
+#[derive(Debug)]
+pub struct InFlight {
+    sent: HashMap<String, Vec<(Instant, IntervalSet)>>,
+    /// Smoothed round trip and its mean deviation, once there is a sample.
+    rtt: Option<(Duration, Duration)>,
+    initial_timeout: Duration,
+}
+
+impl InFlight {
+    /// `initial_timeout` applies until the first round trip is measured.
+    pub fn new(initial_timeout: Duration) -> Self {
+        InFlight { sent: HashMap::new(), rtt: None, initial_timeout }
+    }
+
+    /// How long a value may stay unacknowledged before it is overdue.
+    pub fn timeout(&self) -> Duration {
+        match self.rtt {
+            Some((srtt, rttvar)) => srtt + (rttvar * 4).max(GRANULARITY),
+            None => self.initial_timeout,
+        }
+    }
+
+    /// Records that `values` went to `peer` at `now`, superseding earlier sends of them.
+    pub fn sent(&mut self, peer: &str, values: &IntervalSet, now: Instant) {
+        if values.is_empty() {
+            return;
+        }
+        let sends = self.sent.entry(peer.to_string()).or_default();
+        for (_, earlier) in sends.iter_mut() {
+            *earlier = earlier.difference(values);
+        }
+        sends.retain(|(_, earlier)| !earlier.is_empty());
+        sends.push((now, values.clone()));
+    }
+
+    /// Records that `peer` acknowledged `values` at `now`. Each send it completes is a sample
+    /// of the round trip.
+    pub fn acked(&mut self, peer: &str, values: &IntervalSet, now: Instant) {
+        let Some(sends) = self.sent.get_mut(peer) else {
+            return;
+        };
+        let mut samples = Vec::new();
+        for (at, pending) in sends.iter_mut() {
+            if pending.intersection(values).is_empty() {
+                continue;
+            }
+            *pending = pending.difference(values);
+            if pending.is_empty() {
+                samples.push(now.saturating_duration_since(*at));
+            }
+        }
+        sends.retain(|(_, pending)| !pending.is_empty());
+        for sample in samples {
+            self.rtt = Some(match self.rtt {
+                None => (sample, sample / 2),
+                Some((srtt, rttvar)) => (srtt * 7 / 8 + sample / 8, rttvar * 3 / 4 + srtt.abs_diff(sample) / 4),
+            });
+        }
+    }
+
+    /// The part of `unacknowledged` not sent to `peer` within the last timeout before `now`:
+    /// never sent, or sent long enough ago to be presumed lost. Sends of values no longer
+    /// in `unacknowledged` are forgotten.
+    pub fn overdue(&mut self, peer: &str, unacknowledged: &IntervalSet, now: Instant) -> IntervalSet {
+        let timeout = self.timeout();
+        let Some(sends) = self.sent.get_mut(peer) else {
+            return unacknowledged.clone();
+        };
+        let mut recent = IntervalSet::new();
+        for (at, pending) in sends.iter_mut() {
+            *pending = pending.intersection(unacknowledged);
+            if now.saturating_duration_since(*at) < timeout {
+                recent.union(pending);
+            }
+        }
+        sends.retain(|(_, pending)| !pending.is_empty());
+        unacknowledged.difference(&recent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(range: std::ops::Range<usize>) -> IntervalSet {
+        range.collect()
+    }
+
+    #[test]
+    fn only_values_unacknowledged_for_a_timeout_are_overdue() {
+        let mut in_flight = InFlight::new(Duration::from_millis(500));
+        let start = Instant::now();
+        in_flight.sent("n1", &values(0..10), start);
+        in_flight.sent("n1", &values(10..20), start + Duration::from_millis(300));
+
+        let at = |millis| start + Duration::from_millis(millis);
+        assert!(in_flight.overdue("n1", &values(0..20), at(400)).is_empty());
+        assert_eq!(in_flight.overdue("n1", &values(0..25), at(400)), values(20..25));
+        assert_eq!(in_flight.overdue("n1", &values(0..20), at(600)), values(0..10));
+        assert_eq!(in_flight.overdue("n2", &values(0..20), at(600)), values(0..20));
+
+        // Sending again restarts the clock of just those values.
+        in_flight.sent("n1", &values(0..5), at(600));
+        assert_eq!(in_flight.overdue("n1", &values(0..20), at(900)), values(5..20));
+    }
+
+    #[test]
+    fn acknowledgements_tune_the_timeout_to_the_round_trip() {
+        let mut in_flight = InFlight::new(Duration::from_secs(1));
+        assert_eq!(in_flight.timeout(), Duration::from_secs(1));
+        let mut now = Instant::now();
+        for value in 0..50 {
+            in_flight.sent("n1", &values(value..value + 1), now);
+            now += Duration::from_millis(200);
+            in_flight.acked("n1", &values(value..value + 1), now);
+        }
+        let timeout = in_flight.timeout();
+        assert!(timeout >= Duration::from_millis(210) && timeout < Duration::from_millis(260), "{timeout:?}");
+
+        // A partial acknowledgement is no sample, but still counts.
+        in_flight.sent("n1", &values(100..110), now);
+        in_flight.acked("n1", &values(100..105), now + Duration::from_secs(5));
+        assert_eq!(in_flight.timeout(), timeout);
+        let later = now + Duration::from_secs(6);
+        assert_eq!(in_flight.overdue("n1", &values(100..110), later), values(100..110));
+        assert!(in_flight.overdue("n1", &values(105..110), now).is_empty());
+    }
+}
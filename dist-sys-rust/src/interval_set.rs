@@ -0,0 +1,180 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// Set of integers stored as sorted, disjoint, non-adjacent inclusive runs.
+///
+/// Serialized as the list of runs, e.g. `[[1,40],[52,60]]`, so dense sets stay small on the wire.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntervalSet {
+    /// Start of each run mapped to its (inclusive) end.
+    runs: BTreeMap<usize, usize>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        IntervalSet::default()
+    }
+
+    /// Adds `value`; returns whether it was new.
+    pub fn insert(&mut self, value: usize) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.insert_run(value, value);
+        true
+    }
+
+    /// Adds every value in `start..=end`, merging with the runs it touches.
+    pub fn insert_run(&mut self, start: usize, end: usize) {
+        if start > end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        let touching: Vec<(usize, usize)> = self.runs
+            .range(..=end.saturating_add(1))
+            .rev()
+            .take_while(|(_, run_end)| run_end.saturating_add(1) >= start)
+            .map(|(run_start, run_end)| (*run_start, *run_end))
+            .collect();
+        for (run_start, run_end) in touching {
+            self.runs.remove(&run_start);
+            start = start.min(run_start);
+            end = end.max(run_end);
+        }
+        self.runs.insert(start, end);
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.runs
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    /// Adds every value of `other`.
+    pub fn union(&mut self, other: &IntervalSet) {
+        for (start, end) in other.runs() {
+            self.insert_run(start, end);
+        }
+    }
+
+    /// Values of `self` that are not in `other`.
+    pub fn difference(&self, other: &IntervalSet) -> IntervalSet {
+        let mut result = IntervalSet::new();
+        for (start, end) in self.runs() {
+            let mut cuts: Vec<(&usize, &usize)> = other.runs
+                .range(..=end)
+                .rev()
+                .take_while(|(_, cut_end)| **cut_end >= start)
+                .collect();
+            cuts.reverse();
+            // First value of this run not yet covered or emitted; `None` once the run is exhausted.
+            let mut next = Some(start);
+            for (cut_start, cut_end) in cuts {
+                let Some(from) = next else {
+                    break;
+                };
+                if *cut_start > from {
+                    result.runs.insert(from, cut_start - 1);
+                }
+                next = (*cut_end < end).then(|| cut_end + 1);
+            }
+            if let Some(from) = next {
+                result.runs.insert(from, end);
+            }
+        }
+        result
+    }
+
+    /// Values of `self` that are also in `other`.
+    pub fn intersection(&self, other: &IntervalSet) -> IntervalSet {
+        self.difference(&self.difference(other))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Number of values, not of runs.
+    pub fn len(&self) -> usize {
+        self.runs().map(|(start, end)| end - start + 1).sum()
+    }
+
+    pub fn runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.runs.iter().map(|(start, end)| (*start, *end))
+    }
+
+    /// Every value, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.runs().flat_map(|(start, end)| start..=end)
+    }
+}
+
+impl FromIterator<usize> for IntervalSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(values: I) -> Self {
+        let mut set = IntervalSet::new();
+        for value in values {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl Serialize for IntervalSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.runs().map(|(start, end)| [start, end]))
+    }
+}
+
+impl<'de> Deserialize<'de> for IntervalSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let runs: Vec<(usize, usize)> = Vec::deserialize(deserializer)?;
+        let mut set = IntervalSet::new();
+        for (start, end) in runs {
+            set.insert_run(start, end);
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn insert_merges_adjacent_and_overlapping_runs() {
+        let mut set: IntervalSet = [1, 2, 3, 7, 9].into_iter().collect();
+        assert_eq!(set.runs().collect::<Vec<_>>(), [(1, 3), (7, 7), (9, 9)]);
+        assert!(set.insert(8));
+        assert!(!set.insert(2));
+        assert_eq!(set.runs().collect::<Vec<_>>(), [(1, 3), (7, 9)]);
+        set.insert_run(0, 7);
+        assert_eq!(set.runs().collect::<Vec<_>>(), [(0, 9)]);
+        assert_eq!(set.len(), 10);
+        assert!(set.contains(0) && set.contains(9) && !set.contains(10));
+    }
+
+    #[test]
+    fn difference_keeps_only_uncovered_values() {
+        let set: IntervalSet = (1..=20).collect();
+        let other: IntervalSet = (0..=3).chain(8..=10).chain([15]).collect();
+        assert_eq!(set.difference(&other).runs().collect::<Vec<_>>(), [(4, 7), (11, 14), (16, 20)]);
+        assert!(set.difference(&set).is_empty());
+        assert_eq!(set.difference(&IntervalSet::new()), set);
+        assert_eq!(set.intersection(&other).runs().collect::<Vec<_>>(), [(1, 3), (8, 10), (15, 15)]);
+
+        let mut union = set.difference(&other);
+        union.union(&other);
+        assert_eq!(union.runs().collect::<Vec<_>>(), [(0, 20)]);
+    }
+
+    #[test]
+    fn serializes_as_runs() {
+        let set: IntervalSet = (1..=40).chain(52..=60).collect();
+        assert_eq!(serde_json::to_value(&set).unwrap(), json!([[1, 40], [52, 60]]));
+        let parsed: IntervalSet = serde_json::from_value(json!([[52, 60], [1, 40], [41, 41]])).unwrap();
+        assert_eq!(parsed.runs().collect::<Vec<_>>(), [(1, 41), (52, 60)]);
+        assert_eq!(parsed.iter().take(3).collect::<Vec<_>>(), [1, 2, 3]);
+    }
+}
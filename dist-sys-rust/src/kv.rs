@@ -0,0 +1,221 @@
+use crate::error::{Error, ErrorCode};
+use crate::node::Output;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The key/value services Maelstrom runs next to the nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvService {
+    Lin,
+    Seq,
+    Lww,
+}
+
+impl KvService {
+    pub fn node_id(self) -> &'static str {
+        match self {
+            KvService::Lin => "lin-kv",
+            KvService::Seq => "seq-kv",
+            KvService::Lww => "lww-kv",
+        }
+    }
+}
+
+/// Why a key/value operation did not succeed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError<V> {
+    KeyNotFound,
+    /// `cas` found a different value. Maelstrom only reports it in the error text, so
+    /// `current` is `None` when that text cannot be decoded as a `V`.
+    PreconditionFailed { current: Option<V> },
+    Other(Error),
+}
+
+impl<V: DeserializeOwned> From<Error> for KvError<V> {
+    fn from(error: Error) -> Self {
+        match error.code {
+            ErrorCode::KeyDoesNotExist => KvError::KeyNotFound,
+            ErrorCode::PreconditionFailed => KvError::PreconditionFailed {
+                current: current_value(&error.text),
+            },
+            _ => KvError::Other(error),
+        }
+    }
+}
+
+/// Extracts `X` from the services' "current value X is not Y" message.
+fn current_value<V: DeserializeOwned>(text: &str) -> Option<V> {
+    let (current, _expected) = text.strip_prefix("current value ")?.split_once(" is not ")?;
+    serde_json::from_str(current).ok()
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvRequest<K, V> {
+    Read { key: K },
+    Write { key: K, value: V },
+    Cas { key: K, from: V, to: V, create_if_not_exists: bool },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum KvReply<V> {
+    ReadOk { value: V },
+    WriteOk,
+    CasOk,
+}
+
+/// Typed client for `lin-kv`, `seq-kv` and `lww-kv`.
+///
+/// Each operation is an [`Output::call`]: its outcome reaches the node as the injected
+/// payload built by `on_reply`.
+#[derive(Debug, Clone, Copy)]
+pub struct KvClient {
+    service: KvService,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub fn new(service: KvService) -> Self {
+        KvClient { service, timeout: DEFAULT_TIMEOUT }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        KvClient { timeout, ..self }
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    pub fn read<K, V, IP>(
+        &self,
+        output: &mut Output<IP>,
+        key: K,
+        on_reply: impl FnOnce(Result<V, KvError<V>>) -> IP + 'static,
+    ) -> anyhow::Result<usize>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+    {
+        let request: KvRequest<K, V> = KvRequest::Read { key };
+        output.call(self.service.node_id(), request, self.timeout, move |reply| {
+            on_reply(match reply {
+                Ok(KvReply::ReadOk { value }) => Ok(value),
+                Ok(_) => Err(unexpected_reply("read")),
+                Err(error) => Err(error.into()),
+            })
+        })
+    }
+
+    pub fn write<K, V, IP>(
+        &self,
+        output: &mut Output<IP>,
+        key: K,
+        value: V,
+        on_reply: impl FnOnce(Result<(), KvError<V>>) -> IP + 'static,
+    ) -> anyhow::Result<usize>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+    {
+        output.call(self.service.node_id(), KvRequest::Write { key, value }, self.timeout, move |reply| {
+            on_reply(match reply {
+                Ok(KvReply::<V>::WriteOk) => Ok(()),
+                Ok(_) => Err(unexpected_reply("write")),
+                Err(error) => Err(error.into()),
+            })
+        })
+    }
+
+    pub fn cas<K, V, IP>(
+        &self,
+        output: &mut Output<IP>,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        on_reply: impl FnOnce(Result<(), KvError<V>>) -> IP + 'static,
+    ) -> anyhow::Result<usize>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+    {
+        let request = KvRequest::Cas { key, from, to, create_if_not_exists };
+        output.call(self.service.node_id(), request, self.timeout, move |reply| {
+            on_reply(match reply {
+                Ok(KvReply::<V>::CasOk) => Ok(()),
+                Ok(_) => Err(unexpected_reply("cas")),
+                Err(error) => Err(error.into()),
+            })
+        })
+    }
+}
+
+fn unexpected_reply<V>(operation: &str) -> KvError<V> {
+    KvError::Other(Error::new(ErrorCode::Crash, format!("unexpected reply to {operation}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Buffer;
+    use serde_json::json;
+
+    #[test]
+    fn requests_go_to_the_selected_service() {
+        let buffer = Buffer::default();
+        let mut output: Output<()> = Output::new("n1".to_string(), buffer.clone());
+        KvClient::new(KvService::Seq).read(&mut output, "k", |_: Result<u64, _>| ()).unwrap();
+        KvClient::new(KvService::Lin).write(&mut output, "k", 3, |_| ()).unwrap();
+        KvClient::new(KvService::Lww).cas(&mut output, "k", 3, 4, true, |_| ()).unwrap();
+
+        let sent = buffer.messages();
+        assert_eq!(sent[0]["dest"], "seq-kv");
+        assert_eq!(sent[0]["body"], json!({"type": "read", "msg_id": 1, "in_reply_to": null, "key": "k"}));
+        assert_eq!(sent[1]["dest"], "lin-kv");
+        assert_eq!(sent[1]["body"], json!({"type": "write", "msg_id": 2, "in_reply_to": null, "key": "k", "value": 3}));
+        assert_eq!(sent[2]["dest"], "lww-kv");
+        assert_eq!(
+            sent[2]["body"],
+            json!({"type": "cas", "msg_id": 3, "in_reply_to": null, "key": "k", "from": 3, "to": 4, "create_if_not_exists": true})
+        );
+    }
+
+    #[test]
+    fn replies_become_structured_results() {
+        let mut output: Output<Result<Vec<u64>, KvError<Vec<u64>>>> = Output::new("n1".to_string(), Buffer::default());
+        let kv = KvClient::new(KvService::Lin);
+        kv.read(&mut output, 1, |reply| reply).unwrap();
+        kv.read(&mut output, 2, |reply| reply).unwrap();
+        kv.cas(&mut output, 3, vec![1], vec![1, 2], false, |reply| reply.map(|()| Vec::new())).unwrap();
+        kv.cas(&mut output, 4, vec![1], vec![1, 2], false, |reply| reply.map(|()| Vec::new())).unwrap();
+
+        assert_eq!(output.calls.complete(1, json!({"type": "read_ok", "value": [1, 2]})), Some(Ok(vec![1, 2])));
+        assert_eq!(
+            output.calls.complete(2, json!({"type": "error", "code": 20, "text": "key does not exist"})),
+            Some(Err(KvError::KeyNotFound))
+        );
+        assert_eq!(
+            output.calls.complete(3, json!({"type": "error", "code": 22, "text": "current value [7] is not [1]"})),
+            Some(Err(KvError::PreconditionFailed { current: Some(vec![7]) }))
+        );
+        assert_eq!(
+            output.calls.complete(4, json!({"type": "error", "code": 11, "text": "busy"})),
+            Some(Err(KvError::Other(Error::new(ErrorCode::TemporarilyUnavailable, "busy"))))
+        );
+    }
+
+    #[test]
+    fn undecodable_current_value_is_none() {
+        let error = Error::new(ErrorCode::PreconditionFailed, "expected 1, got 2");
+        assert_eq!(KvError::<u64>::from(error), KvError::PreconditionFailed { current: None });
+        assert_eq!(current_value::<u64>("current value 41 is not 40"), Some(41));
+    }
+}
@@ -0,0 +1,40 @@
+//! Shared Maelstrom protocol types and runtime used by every workload in `src/bin`.
+
+mod digest;
+mod durable;
+mod error;
+mod flake;
+mod fnv;
+mod hyparview;
+mod in_flight;
+mod interval_set;
+mod kv;
+mod message;
+mod node;
+mod outbox;
+mod plumtree;
+mod random;
+mod rpc;
+mod sequence;
+#[doc(hidden)]
+pub mod testing;
+mod topology;
+mod tso;
+
+pub use digest::{bucket_values, Digest};
+pub use durable::{read_number, replace_file};
+pub use error::{Error, ErrorCode};
+pub use flake::FlakeGenerator;
+pub use fnv::Fnv1a;
+pub use hyparview::{Action, HyParView};
+pub use in_flight::InFlight;
+pub use interval_set::IntervalSet;
+pub use kv::{KvClient, KvError, KvService};
+pub use message::{wait_for_initialization, Body, Init, InitPayload, Message};
+pub use node::{main_loop, Event, Injector, Node, Output};
+pub use outbox::{Batch, Outbox};
+pub use plumtree::Plumtree;
+pub use random::{random_below, XorShift};
+pub use sequence::PersistentSequence;
+pub use topology::Topology;
+pub use tso::{Timestamp, TsoClient};
@@ -0,0 +1,158 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Lines, Write};
+
+/// Envelope of every message exchanged with Maelstrom.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message<T> {
+    pub src: String,
+    #[serde(rename = "dest")]
+    pub dst: String,
+    pub body: Body<T>,
+}
+
+impl<T> Message<T> where T: Serialize {
+    /// Swaps source and destination and points `in_reply_to` at the original `msg_id`.
+    pub fn into_reply(self, id: Option<usize>) -> Self {
+        Message {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                msg_id: id,
+                in_reply_to: self.body.msg_id,
+                payload: self.body.payload,
+            }
+        }
+    }
+
+    /// Writes the message as a single newline-terminated JSON line.
+    pub fn send(&self, output: &mut impl Write) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *output, &self)?;
+        output.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Body<T> {
+    pub msg_id: Option<usize>,
+    pub in_reply_to: Option<usize>,
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitPayload {
+    Init(Init),
+    InitOk,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Init {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+}
+
+/// Reads the `init` message from `input` and answers it with `init_ok`.
+pub fn wait_for_initialization<B: BufRead>(input: &mut Lines<B>, output: &mut impl Write) -> anyhow::Result<Init> {
+    let msg: Message<InitPayload> = serde_json::from_str(
+        &input
+        .next()
+        .context("no message received")??)?;
+    let InitPayload::Init(init_msg) = msg.body.payload.clone() else {
+        bail!("Expected init message")
+    };
+    let mut reply = msg.into_reply(Some(0));
+    reply.body.payload = InitPayload::InitOk;
+    reply.send(output)?;
+    Ok(init_msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    #[test]
+    fn deserializes_maelstrom_envelope() {
+        let msg: Message<Payload> = serde_json::from_value(json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 1, "echo": "hello" }
+        })).unwrap();
+        assert_eq!(msg.src, "c1");
+        assert_eq!(msg.dst, "n1");
+        assert_eq!(msg.body.msg_id, Some(1));
+        assert_eq!(msg.body.in_reply_to, None);
+        assert_eq!(msg.body.payload, Payload::Echo { echo: "hello".to_string() });
+    }
+
+    #[test]
+    fn into_reply_swaps_addresses_and_links_msg_id() {
+        let msg = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body { msg_id: Some(7), in_reply_to: None, payload: Payload::Echo { echo: "x".to_string() } },
+        };
+        let reply = msg.into_reply(Some(3));
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.dst, "c1");
+        assert_eq!(reply.body.msg_id, Some(3));
+        assert_eq!(reply.body.in_reply_to, Some(7));
+    }
+
+    #[test]
+    fn send_writes_one_json_line() {
+        let msg = Message {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: Body { msg_id: Some(1), in_reply_to: Some(2), payload: Payload::EchoOk { echo: "x".to_string() } },
+        };
+        let mut buf = Vec::new();
+        msg.send(&mut buf).unwrap();
+        let line = String::from_utf8(buf).unwrap();
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value, json!({
+            "src": "n1",
+            "dest": "c1",
+            "body": { "type": "echo_ok", "msg_id": 1, "in_reply_to": 2, "echo": "x" }
+        }));
+    }
+
+    #[test]
+    fn initialization_replies_init_ok() {
+        let input = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let mut lines = Cursor::new(format!("{input}\n")).lines();
+        let mut buf = Vec::new();
+        let init = wait_for_initialization(&mut lines, &mut buf).unwrap();
+        assert_eq!(init.node_id, "n1");
+        assert_eq!(init.node_ids, vec!["n1", "n2"]);
+        let value: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(value, json!({
+            "src": "n1",
+            "dest": "c0",
+            "body": { "type": "init_ok", "msg_id": 0, "in_reply_to": 1 }
+        }));
+    }
+
+    #[test]
+    fn initialization_rejects_other_messages() {
+        let input = r#"{"src":"c0","dest":"n1","body":{"type":"init_ok","msg_id":1}}"#;
+        let mut lines = Cursor::new(format!("{input}\n")).lines();
+        assert!(wait_for_initialization(&mut lines, &mut Vec::new()).is_err());
+        assert!(wait_for_initialization(&mut Cursor::new("").lines(), &mut Vec::new()).is_err());
+    }
+}
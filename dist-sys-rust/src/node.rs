@@ -0,0 +1,530 @@
+use crate::message::{wait_for_initialization, Body, Init, Message};
+use crate::error::{Error, ErrorCode, ErrorPayload};
+use crate::rpc::Calls;
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Everything a node can be woken up by.
+#[derive(Debug, Clone)]
+pub enum Event<P, IP = ()> {
+    Message(Message<P>),
+    Injected(IP),
+    Eof,
+}
+
+/// A workload state machine driven by [`main_loop`].
+pub trait Node<S, P, IP = ()> {
+    fn from_init(state: S, init: Init, inject: Injector<IP>) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    fn step(&mut self, event: Event<P, IP>, output: &mut Output<IP>) -> anyhow::Result<()>;
+}
+
+enum Input<IP> {
+    Line(String),
+    Injected(IP),
+    Eof,
+}
+
+/// Handle used by a node to feed its own events (timers, background work) into the event loop.
+pub struct Injector<IP>(mpsc::Sender<Input<IP>>);
+
+impl<IP> Clone for Injector<IP> {
+    fn clone(&self) -> Self {
+        Injector(self.0.clone())
+    }
+}
+
+impl<IP> Injector<IP> {
+    /// Injector not connected to any event loop, whose events are dropped; lets tests build a
+    /// node and drive its `step` by hand.
+    pub fn disconnected() -> Self {
+        Injector(mpsc::channel().0)
+    }
+
+    pub fn send(&self, payload: IP) -> Result<(), mpsc::SendError<IP>> {
+        self.0.send(Input::Injected(payload)).map_err(|mpsc::SendError(input)| match input {
+            Input::Injected(payload) => mpsc::SendError(payload),
+            Input::Line(_) | Input::Eof => unreachable!("only injected payloads are sent"),
+        })
+    }
+
+    /// Spawns a thread that injects `payload` every `interval` until the event loop stops.
+    pub fn every(&self, interval: Duration, payload: IP)
+    where
+        IP: Clone + Send + 'static,
+    {
+        let tx = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if tx.send(payload.clone()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Spawns a thread that injects `payload` once, after `delay`.
+    pub fn after(&self, delay: Duration, payload: IP)
+    where
+        IP: Send + 'static,
+    {
+        let tx = self.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let _ = tx.send(payload);
+        });
+    }
+}
+
+/// Where a node writes its messages; also hands out this node's `msg_id`s and tracks its outstanding RPCs.
+pub struct Output<IP = ()> {
+    node_id: String,
+    next_msg_id: usize,
+    writer: Box<dyn Write>,
+    pub(crate) calls: Calls<IP>,
+}
+
+impl<IP> Output<IP> {
+    pub fn new(node_id: String, writer: impl Write + 'static) -> Self {
+        Output { node_id, next_msg_id: 1, writer: Box::new(writer), calls: Calls::default() }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn next_msg_id(&mut self) -> usize {
+        let id = self.next_msg_id;
+        self.next_msg_id += 1;
+        id
+    }
+
+    /// Sends `payload` from this node to `dst` under a fresh `msg_id`.
+    pub fn send<T: Serialize>(&mut self, dst: &str, payload: T) -> anyhow::Result<()> {
+        self.send_new(dst, payload)?;
+        Ok(())
+    }
+
+    fn send_new<T: Serialize>(&mut self, dst: &str, payload: T) -> anyhow::Result<usize> {
+        let msg_id = self.next_msg_id();
+        let message = Message {
+            src: self.node_id.clone(),
+            dst: dst.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            }
+        };
+        message.send(self)?;
+        Ok(msg_id)
+    }
+
+    /// Sends `payload` to `dst` as a request and returns its `msg_id`.
+    ///
+    /// The reply (decoded as `R`), an `error` reply, or a timeout after `timeout` is passed to
+    /// `on_reply`, whose result is delivered to the node as [`Event::Injected`].
+    pub fn call<T, R>(
+        &mut self,
+        dst: &str,
+        payload: T,
+        timeout: Duration,
+        on_reply: impl FnOnce(Result<R, Error>) -> IP + 'static,
+    ) -> anyhow::Result<usize>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let msg_id = self.send_new(dst, payload)?;
+        self.calls.insert(msg_id, Instant::now() + timeout, on_reply);
+        Ok(msg_id)
+    }
+
+    /// Answers request `in_reply_to` from `dst` with an `error` message.
+    pub fn send_error(&mut self, dst: &str, in_reply_to: usize, error: Error) -> anyhow::Result<()> {
+        let message = Message {
+            src: self.node_id.clone(),
+            dst: dst.to_string(),
+            body: Body {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: Some(in_reply_to),
+                payload: ErrorPayload::Error(error),
+            }
+        };
+        message.send(self)
+    }
+}
+
+impl<IP> Write for Output<IP> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Runs node `N` over stdin/stdout: answers `init`, then feeds every message and injected event to `step`.
+pub fn main_loop<S, N, P, IP>(init_state: S) -> anyhow::Result<()>
+where
+    N: Node<S, P, IP>,
+    P: DeserializeOwned,
+    IP: Send + 'static,
+{
+    run::<S, N, P, IP>(init_state, BufReader::new(io::stdin()), io::stdout().lock())
+}
+
+fn run<S, N, P, IP>(init_state: S, input: impl BufRead + Send + 'static, output: impl Write + 'static) -> anyhow::Result<()>
+where
+    N: Node<S, P, IP>,
+    P: DeserializeOwned,
+    IP: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut output = output;
+    let mut lines = input.lines();
+
+    let init = wait_for_initialization(&mut lines, &mut output)
+        .context("Expected init message")?;
+    let mut output = Output::new(init.node_id.clone(), output);
+    let mut node = N::from_init(init_state, init, Injector(tx.clone()))?;
+
+    let stdin_thread = thread::spawn(move || {
+        for line in lines {
+            let line = line.context("no message received")?;
+            if tx.send(Input::Line(line)).is_err() {
+                bail!("Error tx send");
+            }
+        }
+        let _ = tx.send(Input::Eof);
+        Ok(())
+    });
+
+    loop {
+        let input = match output.calls.next_deadline() {
+            Some(deadline) => match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(input) => Some(input),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(input) => Some(input),
+                Err(mpsc::RecvError) => break,
+            },
+        };
+        for payload in output.calls.expire(Instant::now()) {
+            node.step(Event::Injected(payload), &mut output).context("node failed processing rpc timeout")?;
+        }
+        let Some(input) = input else {
+            continue;
+        };
+
+        let event = match input {
+            Input::Line(line) => match parse_line(&line, &mut output)? {
+                Some(event) => event,
+                None => continue,
+            },
+            Input::Injected(payload) => Event::Injected(payload),
+            Input::Eof => Event::Eof,
+        };
+        let eof = matches!(event, Event::Eof);
+        let request = match &event {
+            Event::Message(msg) if msg.body.in_reply_to.is_none() => {
+                msg.body.msg_id.map(|msg_id| (msg.src.clone(), msg_id))
+            }
+            _ => None,
+        };
+        if let Err(e) = node.step(event, &mut output) {
+            let Some((src, msg_id)) = request else {
+                return Err(e.context("node failed processing message"));
+            };
+            let error = match e.downcast_ref::<Error>() {
+                Some(error) => error.clone(),
+                None => {
+                    eprintln!("node failed processing message {msg_id} from {src}: {e:#}");
+                    Error::new(ErrorCode::Crash, format!("{e:#}"))
+                }
+            };
+            output.send_error(&src, msg_id, error)?;
+        }
+        if eof {
+            break;
+        }
+    }
+
+    stdin_thread.join().expect("Error running stdin thread")?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    src: String,
+    body: Header,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    msg_id: Option<usize>,
+    in_reply_to: Option<usize>,
+}
+
+/// Routes replies to pending RPCs; anything else becomes an [`Event::Message`].
+/// Requests that do not fit `P` are answered with `malformed-request`.
+fn parse_line<P, IP>(line: &str, output: &mut Output<IP>) -> anyhow::Result<Option<Event<P, IP>>>
+where
+    P: DeserializeOwned,
+{
+    let envelope: Envelope = match serde_json::from_str(line) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            eprintln!("dropping unreadable message {line}: {e}");
+            return Ok(None);
+        }
+    };
+    if let Some(in_reply_to) = envelope.body.in_reply_to {
+        if output.calls.is_pending(in_reply_to) {
+            let reply: Message<Value> = serde_json::from_str(line).context("Error deserializing message")?;
+            return Ok(output.calls.complete(in_reply_to, reply.body.payload).map(Event::Injected));
+        }
+        // A reply nobody waits for any more (e.g. it arrived after its timeout) is not an error.
+        return match serde_json::from_str(line) {
+            Ok(message) => Ok(Some(Event::Message(message))),
+            Err(e) => {
+                eprintln!("dropping unexpected reply to {in_reply_to}: {e}");
+                Ok(None)
+            }
+        };
+    }
+    match serde_json::from_str(line) {
+        Ok(message) => Ok(Some(Event::Message(message))),
+        Err(e) => {
+            match envelope.body.msg_id {
+                Some(msg_id) => {
+                    let error = Error::new(ErrorCode::MalformedRequest, e.to_string());
+                    output.send_error(&envelope.src, msg_id, error)?;
+                }
+                None => eprintln!("dropping malformed message {line}: {e}"),
+            }
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Buffer;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode {
+        saw_eof: Rc<RefCell<bool>>,
+    }
+
+    impl Node<Rc<RefCell<bool>>, Payload> for EchoNode {
+        fn from_init(saw_eof: Rc<RefCell<bool>>, _init: Init, _inject: Injector<()>) -> anyhow::Result<Self> {
+            Ok(EchoNode { saw_eof })
+        }
+
+        fn step(&mut self, event: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            match event {
+                Event::Message(msg) => {
+                    let mut reply = msg.into_reply(Some(output.next_msg_id()));
+                    let Payload::Echo { echo } = reply.body.payload else {
+                        return Err(Error::new(ErrorCode::NotSupported, "echo_ok is a reply").into());
+                    };
+                    if echo == "boom" {
+                        bail!("cannot echo {echo}");
+                    }
+                    reply.body.payload = Payload::EchoOk { echo };
+                    reply.send(output)?;
+                }
+                Event::Injected(()) => {}
+                Event::Eof => *self.saw_eof.borrow_mut() = true,
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_answers_init_then_steps_every_message() {
+        let input = [
+            json!({"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 5, "echo": "a"}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 6, "echo": "b"}}),
+        ]
+        .iter()
+        .map(|v| format!("{v}\n"))
+        .collect::<String>();
+        let buffer = Buffer::default();
+        let saw_eof = Rc::new(RefCell::new(false));
+
+        run::<_, EchoNode, _, _>(saw_eof.clone(), Cursor::new(input), buffer.clone()).unwrap();
+
+        let sent = buffer.messages();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0]["body"]["type"], "init_ok");
+        assert_eq!(sent[1]["body"], json!({"type": "echo_ok", "msg_id": 1, "in_reply_to": 5, "echo": "a"}));
+        assert_eq!(sent[2]["body"], json!({"type": "echo_ok", "msg_id": 2, "in_reply_to": 6, "echo": "b"}));
+        assert!(*saw_eof.borrow());
+    }
+
+    #[test]
+    fn run_answers_failed_requests_with_errors() {
+        let input = [
+            json!({"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo_ok", "msg_id": 2, "echo": "a"}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 3, "echo": "boom"}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "shout", "msg_id": 4}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 5, "echo": "still alive"}}),
+        ]
+        .iter()
+        .map(|v| format!("{v}\n"))
+        .collect::<String>();
+        let buffer = Buffer::default();
+
+        run::<_, EchoNode, _, _>(Rc::default(), Cursor::new(input), buffer.clone()).unwrap();
+
+        let sent = buffer.messages();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[1]["body"]["type"], "error");
+        assert_eq!(sent[1]["body"]["in_reply_to"], 2);
+        assert_eq!(sent[1]["body"]["code"], 10);
+        assert_eq!(sent[1]["body"]["text"], "echo_ok is a reply");
+        assert_eq!(sent[2]["body"]["in_reply_to"], 3);
+        assert_eq!(sent[2]["body"]["code"], 13);
+        assert_eq!(sent[2]["body"]["text"], "cannot echo boom");
+        assert_eq!(sent[3]["body"]["in_reply_to"], 4);
+        assert_eq!(sent[3]["body"]["code"], 12);
+        assert_eq!(sent[4]["body"]["type"], "echo_ok");
+    }
+
+    #[test]
+    fn output_send_allocates_increasing_msg_ids() {
+        let buffer = Buffer::default();
+        let mut output: Output = Output::new("n1".to_string(), buffer.clone());
+        output.send("n2", Payload::Echo { echo: "x".to_string() }).unwrap();
+        output.send("n3", Payload::Echo { echo: "y".to_string() }).unwrap();
+
+        let sent = buffer.messages();
+        assert_eq!(sent[0]["src"], "n1");
+        assert_eq!(sent[0]["dest"], "n2");
+        assert_eq!(sent[0]["body"]["msg_id"], 1);
+        assert_eq!(sent[1]["dest"], "n3");
+        assert_eq!(sent[1]["body"]["msg_id"], 2);
+    }
+
+    #[test]
+    fn injector_stops_once_loop_is_gone() {
+        let (tx, rx) = mpsc::channel();
+        let inject = Injector(tx);
+        inject.send(1).unwrap();
+        assert!(matches!(rx.recv(), Ok(Input::Injected(1))));
+        drop(rx);
+        assert_eq!(inject.send(2), Err(mpsc::SendError(2)));
+    }
+
+    #[test]
+    fn injector_after_fires_once() {
+        let (tx, rx) = mpsc::channel();
+        Injector(tx).after(Duration::from_millis(10), 7);
+        assert!(matches!(rx.recv_timeout(Duration::from_secs(5)), Ok(Input::Injected(7))));
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum AskPayload {
+        Ask { timeout_ms: u64 },
+        Read,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum ServiceReply {
+        ReadOk { value: usize },
+    }
+
+    type Replies = Rc<RefCell<Vec<(usize, Result<ServiceReply, Error>)>>>;
+
+    struct AskNode {
+        replies: Replies,
+        asked: usize,
+    }
+
+    impl Node<Replies, AskPayload, (usize, Result<ServiceReply, Error>)> for AskNode {
+        fn from_init(replies: Replies, _init: Init, _inject: Injector<(usize, Result<ServiceReply, Error>)>) -> anyhow::Result<Self> {
+            Ok(AskNode { replies, asked: 0 })
+        }
+
+        fn step(
+            &mut self,
+            event: Event<AskPayload, (usize, Result<ServiceReply, Error>)>,
+            output: &mut Output<(usize, Result<ServiceReply, Error>)>,
+        ) -> anyhow::Result<()> {
+            match event {
+                Event::Message(msg) => {
+                    let AskPayload::Ask { timeout_ms } = msg.body.payload else {
+                        bail!("unexpected read");
+                    };
+                    self.asked += 1;
+                    let asked = self.asked;
+                    output.call("svc", AskPayload::Read, Duration::from_millis(timeout_ms), move |reply| (asked, reply))?;
+                }
+                Event::Injected(reply) => self.replies.borrow_mut().push(reply),
+                Event::Eof => {}
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_routes_replies_to_calls_and_times_out_the_rest() {
+        let input = [
+            json!({"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "ask", "msg_id": 2, "timeout_ms": 60_000}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "ask", "msg_id": 3, "timeout_ms": 0}}),
+            json!({"src": "svc", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": 1, "value": 42}}),
+            json!({"src": "svc", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": 2, "value": 43}}),
+        ]
+        .iter()
+        .map(|v| format!("{v}\n"))
+        .collect::<String>();
+        let buffer = Buffer::default();
+        let replies = Replies::default();
+
+        run::<_, AskNode, _, _>(replies.clone(), Cursor::new(input), buffer.clone()).unwrap();
+
+        let sent = buffer.messages();
+        assert_eq!(sent[1]["dest"], "svc");
+        assert_eq!(sent[1]["body"], json!({"type": "read", "msg_id": 1, "in_reply_to": null}));
+        assert_eq!(sent[2]["body"]["msg_id"], 2);
+        assert_eq!(*replies.borrow(), vec![
+            (2, Err(Error::new(ErrorCode::Timeout, "rpc timed out"))),
+            (1, Ok(ServiceReply::ReadOk { value: 42 })),
+        ]);
+    }
+}
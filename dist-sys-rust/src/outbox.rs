@@ -0,0 +1,126 @@
+use crate::interval_set::IntervalSet;
+use std::collections::HashMap;
+use std::mem;
+
+/// One message's worth for a peer: values for it, and acknowledgements of values it sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub seen: IntervalSet,
+    pub acked: IntervalSet,
+}
+
+impl Batch {
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty() && self.acked.is_empty()
+    }
+}
+
+/// Values and acknowledgements waiting to go out, coalesced into one [`Batch`] per peer and flush.
+///
+/// The outbox only decides what goes in each batch; sending them is up to the caller.
+#[derive(Debug)]
+pub struct Outbox {
+    max_batch: usize,
+    queued: HashMap<String, Batch>,
+}
+
+impl Outbox {
+    /// Batches carry at most `max_batch` values, which must be above zero.
+    pub fn new(max_batch: usize) -> Self {
+        Outbox { max_batch: max_batch.max(1), queued: HashMap::new() }
+    }
+
+    /// Queues `seen` for `peer`, returning the full batches to send right away.
+    pub fn push(&mut self, peer: &str, seen: &IntervalSet) -> Vec<Batch> {
+        let queued = self.queued.entry(peer.to_string()).or_default();
+        queued.seen.union(seen);
+        let mut full = Vec::new();
+        while queued.seen.len() >= self.max_batch {
+            full.extend(take_batch(queued, self.max_batch));
+        }
+        full
+    }
+
+    pub fn ack(&mut self, peer: &str, seen: &IntervalSet) {
+        self.queued.entry(peer.to_string()).or_default().acked.union(seen);
+    }
+
+    /// Takes what is queued for `peer` ahead of the next flush, up to one batch.
+    pub fn take(&mut self, peer: &str) -> Option<Batch> {
+        let queued = self.queued.get_mut(peer)?;
+        let batch = take_batch(queued, self.max_batch);
+        if queued.is_empty() {
+            self.queued.remove(peer);
+        }
+        batch
+    }
+
+    /// Takes at most one batch for each peer with anything queued.
+    pub fn flush(&mut self) -> Vec<(String, Batch)> {
+        let batches = self.queued
+            .iter_mut()
+            .filter_map(|(peer, queued)| Some((peer.clone(), take_batch(queued, self.max_batch)?)))
+            .collect();
+        self.queued.retain(|_, queued| !queued.is_empty());
+        batches
+    }
+}
+
+/// Takes every queued acknowledgement and up to `max_batch` of the queued values.
+fn take_batch(queued: &mut Batch, max_batch: usize) -> Option<Batch> {
+    if queued.is_empty() {
+        return None;
+    }
+    let seen: IntervalSet = queued.seen.iter().take(max_batch).collect();
+    queued.seen = queued.seen.difference(&seen);
+    Some(Batch { seen, acked: mem::take(&mut queued.acked) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(range: std::ops::Range<usize>) -> IntervalSet {
+        range.collect()
+    }
+
+    #[test]
+    fn batches_respect_max_batch_and_always_carry_the_acks() {
+        let mut outbox = Outbox::new(100);
+        outbox.ack("n1", &values(500..503));
+        let full = outbox.push("n1", &values(0..250));
+        assert_eq!(full.len(), 2);
+        assert_eq!(full[0], Batch { seen: values(0..100), acked: values(500..503) });
+        assert_eq!(full[1], Batch { seen: values(100..200), acked: IntervalSet::new() });
+
+        outbox.ack("n1", &values(600..601));
+        outbox.push("n2", &values(0..10));
+        let mut flushed = outbox.flush();
+        flushed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(flushed, [
+            ("n1".to_string(), Batch { seen: values(200..250), acked: values(600..601) }),
+            ("n2".to_string(), Batch { seen: values(0..10), acked: IntervalSet::new() }),
+        ]);
+        assert!(outbox.flush().is_empty());
+    }
+
+    #[test]
+    fn acknowledgements_go_out_without_values() {
+        let mut outbox = Outbox::new(1);
+        outbox.ack("n1", &values(7..8));
+        assert_eq!(outbox.flush(), [("n1".to_string(), Batch { seen: IntervalSet::new(), acked: values(7..8) })]);
+        assert!(outbox.push("n1", &IntervalSet::new()).is_empty());
+        assert!(outbox.flush().is_empty());
+    }
+
+    #[test]
+    fn take_sends_one_peer_ahead_of_the_flush() {
+        let mut outbox = Outbox::new(100);
+        outbox.ack("n1", &values(7..8));
+        outbox.push("n1", &values(0..3));
+        outbox.push("n2", &values(0..3));
+        assert_eq!(outbox.take("n1"), Some(Batch { seen: values(0..3), acked: values(7..8) }));
+        assert_eq!(outbox.take("n1"), None);
+        assert_eq!(outbox.flush(), [("n2".to_string(), Batch { seen: values(0..3), acked: IntervalSet::new() })]);
+    }
+}
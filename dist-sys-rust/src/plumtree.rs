@@ -0,0 +1,155 @@
+use crate::interval_set::IntervalSet;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// How long an announced message may stay missing before it is grafted from its announcer.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Plumtree (epidemic broadcast trees) view of a neighborhood: eager links form the broadcast
+/// tree, lazy ones only announce. Decides where messages, announcements and grafts go; sending
+/// them is up to the caller.
+#[derive(Debug)]
+pub struct Plumtree {
+    eager: HashSet<String>,
+    lazy: HashSet<String>,
+    /// Announced messages not received yet, with their announcers and when to graft them.
+    missing: HashMap<usize, (Vec<String>, Instant)>,
+    /// Announcements waiting for the next [`take_announcements`](Plumtree::take_announcements).
+    announcements: HashMap<String, Vec<usize>>,
+}
+
+impl Plumtree {
+    /// Every neighbor starts on the tree; duplicates prune it down.
+    pub fn new(neighborhood: &[String]) -> Self {
+        Plumtree {
+            eager: neighborhood.iter().cloned().collect(),
+            lazy: HashSet::new(),
+            missing: HashMap::new(),
+            announcements: HashMap::new(),
+        }
+    }
+
+    pub fn is_eager(&self, peer: &str) -> bool {
+        self.eager.contains(peer)
+    }
+
+    pub fn is_lazy(&self, peer: &str) -> bool {
+        self.lazy.contains(peer)
+    }
+
+    /// Puts `peer` on the tree: it delivered a new message first, or asked for one.
+    pub fn graft(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    /// Takes `peer` off the tree: it delivered a duplicate, or asked to stop pushing.
+    pub fn prune(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+
+    pub fn forget(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.remove(peer);
+    }
+
+    /// Records that `message` is held, returning the eager peers to push it to; the lazy ones get
+    /// it announced. `from`, the peer it came from, gets neither.
+    pub fn spread(&mut self, message: usize, from: Option<&str>) -> Vec<String> {
+        self.missing.remove(&message);
+        for peer in self.lazy.iter().filter(|peer| Some(peer.as_str()) != from) {
+            self.announcements.entry(peer.clone()).or_default().push(message);
+        }
+        self.eager
+            .iter()
+            .filter(|peer| Some(peer.as_str()) != from)
+            .cloned()
+            .collect()
+    }
+
+    /// Records that `from` announced `messages`; those not in `seen` are grafted unless they
+    /// arrive soon after `now`.
+    pub fn announced(&mut self, from: &str, messages: &[usize], seen: &IntervalSet, now: Instant) {
+        for message in messages.iter().filter(|m| !seen.contains(**m)) {
+            let (announcers, _) = self.missing.entry(*message).or_insert((Vec::new(), now + GRAFT_TIMEOUT));
+            announcers.push(from.to_string());
+        }
+    }
+
+    /// Announcements queued for each lazy peer since the last call.
+    pub fn take_announcements(&mut self) -> HashMap<String, Vec<usize>> {
+        mem::take(&mut self.announcements)
+    }
+
+    /// Announced messages still missing from `seen` at `now` past their deadline, grouped by the
+    /// peer to graft them from, which goes back on the tree. Each announcer is asked in turn
+    /// until one of them gets the message through.
+    pub fn due_grafts(&mut self, seen: &IntervalSet, now: Instant) -> HashMap<String, Vec<usize>> {
+        self.missing.retain(|message, _| !seen.contains(*message));
+        let mut grafts: HashMap<String, Vec<usize>> = HashMap::new();
+        for (message, (announcers, deadline)) in self.missing.iter_mut() {
+            if *deadline > now {
+                continue;
+            }
+            announcers.rotate_left(1);
+            grafts.entry(announcers[0].clone()).or_default().push(*message);
+            *deadline = now + GRAFT_TIMEOUT;
+        }
+        for peer in grafts.keys() {
+            self.graft(peer);
+        }
+        grafts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn prune_and_graft_move_peers_between_eager_and_lazy() {
+        let mut plumtree = Plumtree::new(&peers(&["n1", "n2", "n3"]));
+        assert!(["n1", "n2", "n3"].iter().all(|peer| plumtree.is_eager(peer)));
+
+        plumtree.prune("n2");
+        assert!(!plumtree.is_eager("n2") && plumtree.is_lazy("n2"));
+        assert_eq!(plumtree.spread(7, Some("n1")), ["n3"]);
+        assert_eq!(plumtree.take_announcements(), HashMap::from([("n2".to_string(), vec![7])]));
+        assert!(plumtree.take_announcements().is_empty());
+
+        plumtree.graft("n2");
+        assert!(plumtree.is_eager("n2") && !plumtree.is_lazy("n2"));
+        plumtree.forget("n3");
+        assert!(!plumtree.is_eager("n3") && !plumtree.is_lazy("n3"));
+        let mut pushed = plumtree.spread(8, None);
+        pushed.sort();
+        assert_eq!(pushed, ["n1", "n2"]);
+    }
+
+    #[test]
+    fn grafts_overdue_announcements_from_each_announcer_in_turn() {
+        let mut plumtree = Plumtree::new(&[]);
+        let start = Instant::now();
+        let seen: IntervalSet = [1].into_iter().collect();
+        plumtree.announced("n2", &[1, 5], &seen, start);
+        plumtree.announced("n3", &[5, 6], &seen, start);
+
+        assert!(plumtree.due_grafts(&seen, start).is_empty());
+        let first = plumtree.due_grafts(&seen, start + GRAFT_TIMEOUT);
+        let second = plumtree.due_grafts(&seen, start + GRAFT_TIMEOUT * 2);
+        assert_eq!(first.values().map(Vec::len).sum::<usize>(), 2);
+        let from = |grafts: &HashMap<String, Vec<usize>>| grafts.iter().find(|(_, m)| m.contains(&5)).unwrap().0.clone();
+        assert_ne!(from(&first), from(&second));
+        assert!(plumtree.is_eager("n2") && plumtree.is_eager("n3"));
+
+        // Once they arrive, nothing is grafted any more.
+        let seen: IntervalSet = [1, 5, 6].into_iter().collect();
+        assert!(plumtree.due_grafts(&seen, start + GRAFT_TIMEOUT * 3).is_empty());
+    }
+}
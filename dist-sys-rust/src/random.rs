@@ -0,0 +1,58 @@
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Small xorshift generator: fast and good enough for jitter, peer sampling and shuffles, and
+/// reproducible when several nodes must draw the same numbers from a shared seed.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn seeded(seed: u64) -> Self {
+        // The all-zero state is a fixed point.
+        XorShift(seed | 1)
+    }
+
+    /// Seeded from the randomly keyed std hasher, so every process draws different numbers.
+    pub fn from_entropy() -> Self {
+        XorShift::seeded(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number below `bound`, which must not be zero.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+thread_local! {
+    static RNG: RefCell<XorShift> = RefCell::new(XorShift::from_entropy());
+}
+
+/// A random number below `bound`, which must not be zero, from a per-thread generator.
+pub fn random_below(bound: usize) -> usize {
+    RNG.with(|rng| rng.borrow_mut().below(bound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_generators_agree_and_stay_below_the_bound() {
+        let mut a = XorShift::seeded(42);
+        let mut b = XorShift::seeded(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(XorShift::seeded(0).next_u64(), 0);
+        assert!((0..1000).all(|_| random_below(7) < 7));
+        assert!((0..1000).map(|_| random_below(3)).collect::<std::collections::HashSet<_>>().len() == 3);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::{Error, ErrorCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Instant;
+
+type Handler<IP> = Box<dyn FnOnce(Result<Value, Error>) -> IP>;
+
+struct Call<IP> {
+    deadline: Instant,
+    on_reply: Handler<IP>,
+}
+
+/// Outstanding requests keyed by the `msg_id` their reply will carry in `in_reply_to`.
+pub(crate) struct Calls<IP> {
+    pending: HashMap<usize, Call<IP>>,
+}
+
+impl<IP> Default for Calls<IP> {
+    fn default() -> Self {
+        Calls { pending: HashMap::new() }
+    }
+}
+
+impl<IP> Calls<IP> {
+    pub(crate) fn insert<R>(&mut self, msg_id: usize, deadline: Instant, on_reply: impl FnOnce(Result<R, Error>) -> IP + 'static)
+    where
+        R: DeserializeOwned,
+    {
+        let on_reply = move |reply: Result<Value, Error>| {
+            on_reply(reply.and_then(|body| {
+                serde_json::from_value(body).map_err(malformed)
+            }))
+        };
+        self.pending.insert(msg_id, Call { deadline, on_reply: Box::new(on_reply) });
+    }
+
+    pub(crate) fn is_pending(&self, msg_id: usize) -> bool {
+        self.pending.contains_key(&msg_id)
+    }
+
+    /// Hands the reply `body` to the call waiting on `in_reply_to`, if any.
+    pub(crate) fn complete(&mut self, in_reply_to: usize, body: Value) -> Option<IP> {
+        let call = self.pending.remove(&in_reply_to)?;
+        Some((call.on_reply)(decode_reply(body)))
+    }
+
+    /// Fails every call whose deadline is not after `now`.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<IP> {
+        let mut expired: Vec<(usize, Instant)> = self.pending
+            .iter()
+            .filter(|(_, call)| call.deadline <= now)
+            .map(|(id, call)| (*id, call.deadline))
+            .collect();
+        expired.sort_by_key(|(id, deadline)| (*deadline, *id));
+        expired
+            .into_iter()
+            .filter_map(|(id, _)| self.pending.remove(&id))
+            .map(|call| (call.on_reply)(Err(Error::new(ErrorCode::Timeout, "rpc timed out"))))
+            .collect()
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|call| call.deadline).min()
+    }
+}
+
+fn decode_reply(body: Value) -> Result<Value, Error> {
+    if body.get("type").and_then(Value::as_str) == Some("error") {
+        return Err(serde_json::from_value(body).map_err(malformed)?);
+    }
+    Ok(body)
+}
+
+/// A reply we cannot decode may still stand for an operation that took place, hence `crash`.
+fn malformed(e: serde_json::Error) -> Error {
+    Error::new(ErrorCode::Crash, format!("malformed rpc reply: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Reply {
+        ReadOk { value: usize },
+    }
+
+    #[test]
+    fn complete_decodes_reply_for_matching_call() {
+        let mut calls = Calls::default();
+        calls.insert(3, Instant::now(), |reply: Result<Reply, Error>| reply);
+        assert!(calls.complete(4, json!({"type": "read_ok", "value": 1})).is_none());
+        assert_eq!(
+            calls.complete(3, json!({"type": "read_ok", "value": 9})),
+            Some(Ok(Reply::ReadOk { value: 9 }))
+        );
+        assert!(!calls.is_pending(3));
+    }
+
+    #[test]
+    fn complete_turns_error_bodies_into_errors() {
+        let mut calls = Calls::default();
+        calls.insert(1, Instant::now(), |reply: Result<Reply, Error>| reply);
+        calls.insert(2, Instant::now(), |reply: Result<Reply, Error>| reply);
+        assert_eq!(
+            calls.complete(1, json!({"type": "error", "code": 20, "text": "key does not exist"})),
+            Some(Err(Error::new(ErrorCode::KeyDoesNotExist, "key does not exist")))
+        );
+        assert!(matches!(
+            calls.complete(2, json!({"type": "cas_ok"})),
+            Some(Err(Error { code: ErrorCode::Crash, .. }))
+        ));
+    }
+
+    #[test]
+    fn expire_times_out_only_overdue_calls_in_deadline_order() {
+        let now = Instant::now();
+        let mut calls = Calls::default();
+        calls.insert(1, now + Duration::from_secs(1), move |reply: Result<Reply, Error>| (1, reply));
+        calls.insert(2, now, move |reply: Result<Reply, Error>| (2, reply));
+        calls.insert(3, now + Duration::from_secs(5), move |reply: Result<Reply, Error>| (3, reply));
+
+        assert_eq!(calls.next_deadline(), Some(now));
+        let timeout = Err(Error::new(ErrorCode::Timeout, "rpc timed out"));
+        assert_eq!(calls.expire(now), vec![(2, timeout.clone())]);
+        assert_eq!(calls.expire(now + Duration::from_secs(2)), vec![(1, timeout)]);
+        assert_eq!(calls.next_deadline(), Some(now + Duration::from_secs(5)));
+        assert!(calls.is_pending(3));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::durable::{read_number, replace_file};
+use std::path::PathBuf;
+
+/// Counter that never hands out a value twice, even across restarts.
+///
+/// Values are reserved in blocks: before the first value of a block is returned, the end of
+/// the block is written to `path` as the high-water mark. A restarted counter resumes from the
+/// mark, skipping whatever was left of the block but never reusing a value.
+#[derive(Debug)]
+pub struct PersistentSequence {
+    path: PathBuf,
+    block: u64,
+    next: u64,
+    /// High-water mark on disk: every value below it may have been handed out.
+    reserved: u64,
+}
+
+impl PersistentSequence {
+    /// Resumes from the mark in `path`, or from zero when the file does not exist.
+    pub fn open(path: impl Into<PathBuf>, block: u64) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mark = read_number(&path, "high-water mark")?.unwrap_or(0);
+        Ok(PersistentSequence { path, block: block.max(1), next: mark, reserved: mark })
+    }
+
+    pub fn next_value(&mut self) -> anyhow::Result<u64> {
+        if self.next >= self.reserved {
+            self.checkpoint(self.next + self.block)?;
+        }
+        let value = self.next;
+        self.next += 1;
+        Ok(value)
+    }
+
+    /// Makes `mark` durable before any value below it is handed out; a crash leaves either the
+    /// old mark or the new one.
+    fn checkpoint(&mut self, mark: u64) -> anyhow::Result<()> {
+        replace_file(&self.path, format!("{mark}\n").as_bytes())?;
+        self.reserved = mark;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("maelstrom-sequence-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reserves_blocks_ahead_of_the_values_handed_out() {
+        let path = scratch("blocks");
+        let mut sequence = PersistentSequence::open(&path, 10).unwrap();
+        let values: Vec<u64> = (0..12).map(|_| sequence.next_value().unwrap()).collect();
+        assert_eq!(values, (0..12).collect::<Vec<_>>());
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "20");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_each_mark_before_the_first_value_of_its_block() {
+        let path = scratch("order");
+        let mut sequence = PersistentSequence::open(&path, 10).unwrap();
+        for _ in 0..25 {
+            let value = sequence.next_value().unwrap();
+            let mark: u64 = fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+            assert!(mark > value, "value {value} returned with {mark} on disk");
+            if value.is_multiple_of(10) {
+                assert_eq!(mark, value + 10);
+            }
+        }
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumes_past_every_value_after_a_restart() {
+        let path = scratch("restart");
+        let mut sequence = PersistentSequence::open(&path, 10).unwrap();
+        sequence.next_value().unwrap();
+        sequence.next_value().unwrap();
+        drop(sequence);
+
+        let mut restarted = PersistentSequence::open(&path, 10).unwrap();
+        assert_eq!(restarted.next_value().unwrap(), 10);
+        fs::write(&path, "garbage").unwrap();
+        assert!(PersistentSequence::open(&path, 10).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
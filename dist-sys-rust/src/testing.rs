@@ -0,0 +1,95 @@
+//! Helpers for unit tests that drive a node's `step` by hand. Not part of the library's API.
+
+use crate::message::{Body, Init, Message};
+use crate::node::{Event, Injector, Node, Output};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Writer whose contents stay readable after it is moved into an [`Output`].
+#[derive(Clone, Default)]
+pub struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Buffer {
+    /// Every message written so far, in order.
+    pub fn messages(&self) -> Vec<Value> {
+        let lines = self.0.borrow();
+        lines
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    /// Forgets the messages written so far.
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A node built straight from an [`Init`], with no event loop behind its [`Injector`] and its
+/// output written to a [`Buffer`]. Every step is expected to succeed.
+pub struct TestNode<N, S, P, IP> {
+    pub node: N,
+    pub output: Output<IP>,
+    buffer: Buffer,
+    state: PhantomData<fn(S, P)>,
+}
+
+impl<N, S, P, IP> TestNode<N, S, P, IP>
+where
+    N: Node<S, P, IP>,
+{
+    /// `node_id`'s node in a cluster of `node_ids`.
+    pub fn start(state: S, node_id: &str, node_ids: &[&str]) -> Self {
+        let init = Init {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+        };
+        let node = N::from_init(state, init, Injector::disconnected()).unwrap();
+        let buffer = Buffer::default();
+        TestNode { node, output: Output::new(node_id.to_string(), buffer.clone()), buffer, state: PhantomData }
+    }
+
+    pub fn step(&mut self, event: Event<P, IP>) {
+        self.node.step(event, &mut self.output).unwrap();
+    }
+
+    pub fn inject(&mut self, payload: IP) {
+        self.step(Event::Injected(payload));
+    }
+
+    /// Delivers `payload` from `src` as a request numbered `msg_id`.
+    pub fn deliver(&mut self, src: &str, msg_id: usize, payload: P) {
+        let msg = request(src, self.output.node_id(), msg_id, payload);
+        self.step(Event::Message(msg));
+    }
+
+    /// Destination and body of every message sent since the last call, in order.
+    pub fn sent(&mut self) -> Vec<(String, Value)> {
+        let messages = self.buffer
+            .messages()
+            .into_iter()
+            .map(|m| (m["dest"].as_str().unwrap().to_string(), m["body"].clone()))
+            .collect();
+        self.buffer.clear();
+        messages
+    }
+}
+
+/// A request numbered `msg_id` from `src` to `dst`.
+pub fn request<P>(src: &str, dst: &str, msg_id: usize, payload: P) -> Message<P> {
+    Message { src: src.to_string(), dst: dst.to_string(), body: Body { msg_id: Some(msg_id), in_reply_to: None, payload } }
+}
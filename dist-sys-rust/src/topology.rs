@@ -0,0 +1,174 @@
+use crate::random::XorShift;
+use anyhow::{bail, Context};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Overlay a node can compute on its own from `Init::node_ids`, instead of taking Maelstrom's `topology`.
+///
+/// Every node must see the same `node_ids` in the same order, which Maelstrom guarantees, so
+/// the graphs agree without any coordination. Parsed from `tree`, `kary:<k>`, `grid`, `ring`,
+/// `mesh` or `random:<degree>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Spanning tree of depth one: the first node is linked to every other one.
+    Tree,
+    /// Tree where node `i` is the parent of nodes `k*i + 1 ..= k*i + k`.
+    KaryTree(usize),
+    /// Nodes laid out row by row on a square grid, linked to the cells around them.
+    Grid,
+    Ring,
+    FullMesh,
+    /// Union of `ceil(degree / 2)` random cycles over all nodes, so roughly `degree` links per node.
+    RandomRegular(usize),
+}
+
+impl FromStr for Topology {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<Self> {
+        let (name, param) = match spec.split_once(':') {
+            Some((name, param)) => {
+                let param: usize = param.parse().with_context(|| format!("invalid parameter in topology {spec:?}"))?;
+                if param == 0 {
+                    bail!("topology {spec:?} needs a parameter above zero");
+                }
+                (name, Some(param))
+            }
+            None => (spec, None),
+        };
+        Ok(match (name, param) {
+            ("tree", None) => Topology::Tree,
+            ("kary", Some(k)) => Topology::KaryTree(k),
+            ("grid", None) => Topology::Grid,
+            ("ring", None) => Topology::Ring,
+            ("mesh", None) => Topology::FullMesh,
+            ("random", Some(degree)) => Topology::RandomRegular(degree),
+            _ => bail!("unknown topology {spec:?}, expected tree, kary:<k>, grid, ring, mesh or random:<degree>"),
+        })
+    }
+}
+
+impl Topology {
+    /// Neighbors of `node_id` in this overlay; empty when `node_id` is not one of `node_ids`.
+    pub fn neighbors(self, node_id: &str, node_ids: &[String]) -> Vec<String> {
+        let Some(me) = node_ids.iter().position(|n| n == node_id) else {
+            return Vec::new();
+        };
+        let n = node_ids.len();
+        let indices: BTreeSet<usize> = match self {
+            Topology::Tree if me == 0 => (1..n).collect(),
+            Topology::Tree => BTreeSet::from([0]),
+            Topology::KaryTree(k) => {
+                let parent = (me > 0).then(|| (me - 1) / k);
+                let children = (k * me + 1..=k * me + k).filter(|child| *child < n);
+                parent.into_iter().chain(children).collect()
+            }
+            Topology::Grid => {
+                let width = (1..=n).find(|w| w * w >= n).unwrap_or(1);
+                let (row, col) = (me / width, me % width);
+                let mut cells = BTreeSet::new();
+                if col > 0 {
+                    cells.insert(me - 1);
+                }
+                if col + 1 < width && me + 1 < n {
+                    cells.insert(me + 1);
+                }
+                if row > 0 {
+                    cells.insert(me - width);
+                }
+                if me + width < n {
+                    cells.insert(me + width);
+                }
+                cells
+            }
+            Topology::Ring => BTreeSet::from([(me + n - 1) % n, (me + 1) % n]),
+            Topology::FullMesh => (0..n).collect(),
+            Topology::RandomRegular(degree) => random_cycles(node_ids, degree.div_ceil(2), me),
+        };
+        indices
+            .into_iter()
+            .filter(|i| *i != me)
+            .map(|i| node_ids[i].clone())
+            .collect()
+    }
+}
+
+/// Neighbors of `me` over `cycles` shuffled cycles, seeded from `node_ids` so every node draws the same ones.
+fn random_cycles(node_ids: &[String], cycles: usize, me: usize) -> BTreeSet<usize> {
+    let mut hasher = DefaultHasher::new();
+    node_ids.hash(&mut hasher);
+    let mut rng = XorShift::seeded(hasher.finish());
+
+    let n = node_ids.len();
+    let mut neighbors = BTreeSet::new();
+    for _ in 0..cycles {
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            order.swap(i, rng.below(i + 1));
+        }
+        let at = order.iter().position(|i| *i == me).expect("every index is in the cycle");
+        neighbors.insert(order[(at + n - 1) % n]);
+        neighbors.insert(order[(at + 1) % n]);
+    }
+    neighbors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{i}")).collect()
+    }
+
+    fn neighbors(topology: Topology, node: &str, n: usize) -> Vec<String> {
+        topology.neighbors(node, &nodes(n))
+    }
+
+    #[test]
+    fn parses_every_topology() {
+        assert_eq!("tree".parse::<Topology>().unwrap(), Topology::Tree);
+        assert_eq!("kary:4".parse::<Topology>().unwrap(), Topology::KaryTree(4));
+        assert_eq!("grid".parse::<Topology>().unwrap(), Topology::Grid);
+        assert_eq!("ring".parse::<Topology>().unwrap(), Topology::Ring);
+        assert_eq!("mesh".parse::<Topology>().unwrap(), Topology::FullMesh);
+        assert_eq!("random:3".parse::<Topology>().unwrap(), Topology::RandomRegular(3));
+        assert!("kary".parse::<Topology>().is_err());
+        assert!("kary:0".parse::<Topology>().is_err());
+        assert!("star".parse::<Topology>().is_err());
+    }
+
+    #[test]
+    fn trees_ring_grid_and_mesh_link_the_expected_nodes() {
+        assert_eq!(neighbors(Topology::Tree, "n0", 4), ["n1", "n2", "n3"]);
+        assert_eq!(neighbors(Topology::Tree, "n2", 4), ["n0"]);
+        assert_eq!(neighbors(Topology::KaryTree(2), "n1", 7), ["n0", "n3", "n4"]);
+        assert_eq!(neighbors(Topology::KaryTree(2), "n5", 7), ["n2"]);
+        assert_eq!(neighbors(Topology::Ring, "n0", 5), ["n1", "n4"]);
+        assert_eq!(neighbors(Topology::Grid, "n4", 9), ["n1", "n3", "n5", "n7"]);
+        assert_eq!(neighbors(Topology::Grid, "n5", 7), ["n2", "n4"]);
+        assert_eq!(neighbors(Topology::FullMesh, "n1", 3), ["n0", "n2"]);
+    }
+
+    #[test]
+    fn random_regular_is_symmetric_and_agreed_on() {
+        let ids = nodes(25);
+        let topology = Topology::RandomRegular(4);
+        for id in &ids {
+            let mine = topology.neighbors(id, &ids);
+            assert_eq!(mine, topology.neighbors(id, &ids));
+            assert!((1..=4).contains(&mine.len()));
+            for other in &mine {
+                assert!(topology.neighbors(other, &ids).contains(id));
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_node_has_no_neighbors() {
+        assert!(neighbors(Topology::FullMesh, "n9", 3).is_empty());
+        assert!(neighbors(Topology::Ring, "n0", 1).is_empty());
+    }
+}
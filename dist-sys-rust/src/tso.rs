@@ -0,0 +1,151 @@
+use crate::error::Error;
+use crate::node::Output;
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::time::Duration;
+
+const LIN_TSO: &str = "lin-tso";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TsoRequest {
+    Ts,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TsoReply {
+    TsOk { ts: u64 },
+}
+
+/// A `lin-tso` timestamp plus the caller's position in the batch that shared it.
+///
+/// Every caller in a batch was waiting before the `ts` request was sent, so the pair is
+/// strictly increasing across all calls of this node and still respects real-time order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub ts: u64,
+    pub seq: usize,
+}
+
+/// Client for the `lin-tso` timestamp oracle.
+///
+/// Only one `ts` request is in flight at a time: callers arriving meanwhile are batched
+/// behind it and served by the next request. `W` identifies a caller (e.g. the client
+/// request to answer); `on_reply` turns the oracle's answer into the node's injected
+/// payload, which must be handed back through [`TsoClient::complete`].
+pub struct TsoClient<W, IP> {
+    timeout: Duration,
+    on_reply: fn(Result<u64, Error>) -> IP,
+    in_flight: Vec<W>,
+    waiting: Vec<W>,
+    busy: bool,
+}
+
+impl<W, IP: 'static> TsoClient<W, IP> {
+    pub fn new(on_reply: fn(Result<u64, Error>) -> IP) -> Self {
+        TsoClient {
+            timeout: DEFAULT_TIMEOUT,
+            on_reply,
+            in_flight: Vec::new(),
+            waiting: Vec::new(),
+            busy: false,
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        TsoClient { timeout, ..self }
+    }
+
+    /// Asks for a timestamp on behalf of `waiter`.
+    pub fn ts(&mut self, output: &mut Output<IP>, waiter: W) -> anyhow::Result<()> {
+        self.waiting.push(waiter);
+        if !self.busy {
+            self.flush(output)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the batch answered by `result` and sends the next request if callers are waiting.
+    pub fn complete(&mut self, output: &mut Output<IP>, result: Result<u64, Error>) -> anyhow::Result<Vec<(W, Result<Timestamp, Error>)>> {
+        self.busy = false;
+        let resolved = mem::take(&mut self.in_flight)
+            .into_iter()
+            .enumerate()
+            .map(|(seq, waiter)| (waiter, result.clone().map(|ts| Timestamp { ts, seq })))
+            .collect();
+        if !self.waiting.is_empty() {
+            self.flush(output)?;
+        }
+        Ok(resolved)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.in_flight.len() + self.waiting.len()
+    }
+
+    fn flush(&mut self, output: &mut Output<IP>) -> anyhow::Result<()> {
+        self.in_flight = mem::take(&mut self.waiting);
+        self.busy = true;
+        let on_reply = self.on_reply;
+        output.call(LIN_TSO, TsoRequest::Ts, self.timeout, move |reply| {
+            on_reply(match reply {
+                Ok(TsoReply::TsOk { ts }) => Ok(ts),
+                Err(error) => Err(error),
+            })
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::testing::Buffer;
+    use serde_json::json;
+
+    type Reply = Result<u64, Error>;
+
+    #[test]
+    fn batches_callers_behind_the_request_in_flight() {
+        let buffer = Buffer::default();
+        let mut output: Output<Reply> = Output::new("n1".to_string(), buffer.clone());
+        let mut tso = TsoClient::new(|reply| reply);
+
+        tso.ts(&mut output, "a").unwrap();
+        tso.ts(&mut output, "b").unwrap();
+        tso.ts(&mut output, "c").unwrap();
+        assert_eq!(buffer.messages().len(), 1);
+        assert_eq!(tso.pending(), 3);
+
+        let reply = output.calls.complete(1, json!({"type": "ts_ok", "ts": 10})).unwrap();
+        assert_eq!(tso.complete(&mut output, reply).unwrap(), vec![("a", Ok(Timestamp { ts: 10, seq: 0 }))]);
+        let sent = buffer.messages();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1]["dest"], "lin-tso");
+        assert_eq!(sent[1]["body"]["type"], "ts");
+
+        let reply = output.calls.complete(2, json!({"type": "ts_ok", "ts": 11})).unwrap();
+        assert_eq!(tso.complete(&mut output, reply).unwrap(), vec![
+            ("b", Ok(Timestamp { ts: 11, seq: 0 })),
+            ("c", Ok(Timestamp { ts: 11, seq: 1 })),
+        ]);
+        assert_eq!(buffer.messages().len(), 2);
+        assert_eq!(tso.pending(), 0);
+    }
+
+    #[test]
+    fn failures_reach_every_caller_of_the_batch() {
+        let mut output: Output<Reply> = Output::new("n1".to_string(), Buffer::default());
+        let mut tso = TsoClient::new(|reply| reply);
+        tso.ts(&mut output, 1).unwrap();
+
+        let timeout = Error::new(ErrorCode::Timeout, "rpc timed out");
+        assert_eq!(tso.complete(&mut output, Err(timeout.clone())).unwrap(), vec![(1, Err(timeout))]);
+        assert_eq!(tso.pending(), 0);
+    }
+}